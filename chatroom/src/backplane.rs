@@ -0,0 +1,168 @@
+// When the mapping of a term moves, users still connected to the old instance are in a
+// different copy of the chatroom than those who join the new one. The backplane connects the
+// copies: every chatroom publishes the messages it broadcasts to its own clients, and
+// broadcasts what the chatrooms with the same id on other instances publish.
+//
+// Only NewMessage, NewUser and UserDisconnected are published. Messages received from the
+// backplane are never published again, so they can't loop between instances.
+
+use crate::config::Config;
+use crate::BoxResult;
+use log::info;
+use serde::Deserialize;
+use shared::ServerToClientMessage;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+mod local;
+mod tcp;
+
+pub use self::local::LocalBackplane;
+pub use self::tcp::TcpBackplane;
+
+/// The backplane used by the chatroom service.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackplaneKind {
+    /// Chatrooms are only connected within this process, which is the same as having no
+    /// backplane.
+    Local,
+    /// Chatrooms are connected to those of the instances in `backplane_peers` over TCP.
+    Tcp,
+}
+
+pub trait Backplane: Send + Sync {
+    /// Sends the message to the chatrooms with the id on every other instance.
+    fn publish(&self, chatroom_id: i32, message: &ServerToClientMessage);
+
+    /// Returns the messages published to the chatroom by other instances. The subscription
+    /// ends when the receiver is dropped.
+    fn subscribe(&self, chatroom_id: i32) -> UnboundedReceiver<ServerToClientMessage>;
+}
+
+/// Returns whether the message is shared with the chatrooms on other instances.
+pub fn is_shared(message: &ServerToClientMessage) -> bool {
+    matches!(
+        message,
+        ServerToClientMessage::NewMessage { .. }
+            | ServerToClientMessage::NewUser { .. }
+            | ServerToClientMessage::UserDisconnected { .. }
+    )
+}
+
+/// The chatrooms of this instance subscribed to the backplane.
+#[derive(Default)]
+pub struct Subscribers {
+    chatrooms: Mutex<HashMap<i32, Vec<UnboundedSender<ServerToClientMessage>>>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&self, chatroom_id: i32) -> UnboundedReceiver<ServerToClientMessage> {
+        let (sender, receiver) = unbounded_channel();
+
+        let mut chatrooms = self.chatrooms.lock().unwrap();
+        chatrooms.entry(chatroom_id).or_default().push(sender);
+
+        receiver
+    }
+
+    /// Hands the message to every subscriber of the chatroom, forgetting those that are gone.
+    pub fn deliver(&self, chatroom_id: i32, message: &ServerToClientMessage) {
+        let mut chatrooms = self.chatrooms.lock().unwrap();
+
+        if let Some(subscribers) = chatrooms.get_mut(&chatroom_id) {
+            subscribers.retain(|subscriber| subscriber.send(message.clone()).is_ok());
+
+            if subscribers.is_empty() {
+                chatrooms.remove(&chatroom_id);
+            }
+        }
+    }
+}
+
+/// Opens the backplane selected by the configuration.
+pub async fn open_backplane(config: &Config) -> BoxResult<Arc<dyn Backplane>> {
+    match config.backplane {
+        BackplaneKind::Local => Ok(Arc::new(LocalBackplane::new())),
+        BackplaneKind::Tcp => {
+            info!(
+                "Connecting chatrooms to {} peers over TCP.",
+                config.backplane_peers.len()
+            );
+            let listener = tokio::net::TcpListener::bind(config.backplane_bind_address).await?;
+            Ok(Arc::new(TcpBackplane::new(
+                listener,
+                config.backplane_peers.clone(),
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backplane::{Backplane, LocalBackplane, TcpBackplane};
+    use shared::ServerToClientMessage;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    async fn receive(
+        receiver: &mut UnboundedReceiver<ServerToClientMessage>,
+    ) -> Option<ServerToClientMessage> {
+        tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn local_messages_reach_other_instances() {
+        let first = LocalBackplane::new();
+        let second = first.join();
+
+        let mut first_room = first.subscribe(1);
+        let mut second_room = second.subscribe(1);
+        let mut other_room = second.subscribe(2);
+
+        first.publish(1, &ServerToClientMessage::NewUser { user_id: 7 });
+
+        let message = receive(&mut second_room).await;
+        assert!(matches!(
+            message,
+            Some(ServerToClientMessage::NewUser { user_id: 7 })
+        ));
+
+        // Nothing comes back to the publisher or reaches other chatrooms.
+        assert!(first_room.try_recv().is_err());
+        assert!(other_room.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_messages_reach_peers() {
+        let first_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let first_address = first_listener.local_addr().unwrap();
+        let second_address = second_listener.local_addr().unwrap();
+
+        let first = TcpBackplane::new(first_listener, vec![second_address]);
+        let second = TcpBackplane::new(second_listener, vec![first_address]);
+
+        let mut first_room = first.subscribe(1);
+        let mut second_room = second.subscribe(1);
+
+        first.publish(1, &ServerToClientMessage::UserDisconnected { user_id: 3 });
+        let message = receive(&mut second_room).await;
+        assert!(matches!(
+            message,
+            Some(ServerToClientMessage::UserDisconnected { user_id: 3 })
+        ));
+
+        second.publish(1, &ServerToClientMessage::NewUser { user_id: 4 });
+        let message = receive(&mut first_room).await;
+        assert!(matches!(
+            message,
+            Some(ServerToClientMessage::NewUser { user_id: 4 })
+        ));
+    }
+}
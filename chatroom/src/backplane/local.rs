@@ -0,0 +1,57 @@
+use crate::backplane::{Backplane, Subscribers};
+use shared::ServerToClientMessage;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// A backplane between chatrooms of the same process. Every backplane joined to another stands
+/// in for a separate instance, which is intended for tests.
+pub struct LocalBackplane {
+    subscribers: Arc<Subscribers>,
+    // The subscribers of every backplane joined to this one, including its own.
+    instances: Arc<Mutex<Vec<Arc<Subscribers>>>>,
+}
+
+impl LocalBackplane {
+    pub fn new() -> Self {
+        let subscribers = Arc::new(Subscribers::default());
+
+        LocalBackplane {
+            instances: Arc::new(Mutex::new(vec![subscribers.clone()])),
+            subscribers,
+        }
+    }
+
+    /// Returns a backplane for another instance connected to this one.
+    #[cfg(test)]
+    pub fn join(&self) -> Self {
+        let subscribers = Arc::new(Subscribers::default());
+        self.instances.lock().unwrap().push(subscribers.clone());
+
+        LocalBackplane {
+            subscribers,
+            instances: self.instances.clone(),
+        }
+    }
+}
+
+impl Default for LocalBackplane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backplane for LocalBackplane {
+    fn publish(&self, chatroom_id: i32, message: &ServerToClientMessage) {
+        let instances = self.instances.lock().unwrap();
+
+        for subscribers in instances.iter() {
+            if !Arc::ptr_eq(subscribers, &self.subscribers) {
+                subscribers.deliver(chatroom_id, message);
+            }
+        }
+    }
+
+    fn subscribe(&self, chatroom_id: i32) -> UnboundedReceiver<ServerToClientMessage> {
+        self.subscribers.subscribe(chatroom_id)
+    }
+}
@@ -0,0 +1,143 @@
+use crate::backplane::{Backplane, Subscribers};
+use crate::BoxResult;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use shared::ServerToClientMessage;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+// Instances exchange envelopes as newline delimited JSON. Every instance connects to each of
+// its peers and only writes to that connection, so two instances are connected once they list
+// each other as peers. Messages published while a peer can't be reached are dropped.
+
+/// How long to wait before connecting to a peer again after the connection failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    chatroom_id: i32,
+    message: ServerToClientMessage,
+}
+
+/// A backplane between chatroom instances over TCP.
+pub struct TcpBackplane {
+    subscribers: Arc<Subscribers>,
+    peers: Vec<UnboundedSender<Arc<String>>>,
+}
+
+impl TcpBackplane {
+    /// Accepts envelopes from peers on the listener and sends published ones to `peers`.
+    pub fn new(listener: TcpListener, peers: Vec<SocketAddr>) -> Self {
+        let subscribers = Arc::new(Subscribers::default());
+        tokio::spawn(Self::accept(listener, subscribers.clone()));
+
+        let peers = peers
+            .into_iter()
+            .map(|address| {
+                let (sender, receiver) = unbounded_channel();
+                tokio::spawn(Self::write_to_peer(address, receiver));
+                sender
+            })
+            .collect();
+
+        TcpBackplane { subscribers, peers }
+    }
+
+    async fn accept(listener: TcpListener, subscribers: Arc<Subscribers>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    info!("Backplane peer {} connected.", address);
+                    tokio::spawn(Self::read_from_peer(stream, subscribers.clone()));
+                }
+                Err(error) => {
+                    error!("Failed to accept a backplane connection - {:?}", error);
+                }
+            }
+        }
+    }
+
+    async fn read_from_peer(stream: TcpStream, subscribers: Arc<Subscribers>) {
+        let mut lines = BufReader::new(stream).lines();
+
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => match serde_json::from_str::<Envelope>(&line) {
+                    Ok(envelope) => subscribers.deliver(envelope.chatroom_id, &envelope.message),
+                    Err(error) => error!("Invalid envelope received from a peer - {:?}", error),
+                },
+                Ok(None) => break,
+                Err(error) => {
+                    error!("Failed to read from a backplane peer - {:?}", error);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn write_to_peer(address: SocketAddr, mut receiver: UnboundedReceiver<Arc<String>>) {
+        loop {
+            let mut stream = match TcpStream::connect(address).await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    error!(
+                        "Failed to connect to backplane peer {} - {:?}",
+                        address, error
+                    );
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+
+                    // Whatever was published in the meantime is lost.
+                    while receiver.try_recv().is_ok() {}
+                    continue;
+                }
+            };
+
+            info!("Connected to backplane peer {}.", address);
+
+            loop {
+                let line = match receiver.recv().await {
+                    Some(line) => line,
+                    // The backplane was dropped.
+                    None => return,
+                };
+
+                let result: BoxResult<()> = async {
+                    stream.write_all(line.as_bytes()).await?;
+                    stream.write_all(b"\n").await?;
+                    Ok(())
+                }
+                .await;
+
+                if let Err(error) = result {
+                    error!(
+                        "Failed to write to backplane peer {} - {:?}",
+                        address, error
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Backplane for TcpBackplane {
+    fn publish(&self, chatroom_id: i32, message: &ServerToClientMessage) {
+        let envelope = Envelope {
+            chatroom_id,
+            message: message.clone(),
+        };
+        let line = Arc::new(serde_json::to_string(&envelope).unwrap());
+
+        for peer in &self.peers {
+            let _ = peer.send(line.clone());
+        }
+    }
+
+    fn subscribe(&self, chatroom_id: i32) -> UnboundedReceiver<ServerToClientMessage> {
+        self.subscribers.subscribe(chatroom_id)
+    }
+}
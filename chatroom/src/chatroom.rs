@@ -1,18 +1,31 @@
+use crate::backplane::{self, Backplane};
+use crate::config::Config;
+use crate::connection::Connection;
+use crate::metrics::Metrics;
 use crate::model::Model;
-use async_recursion::async_recursion;
 use axum::extract::ws::{Message, WebSocket};
+use chrono::Utc;
+use futures::future::join_all;
 use futures::stream::SplitSink;
-use futures::SinkExt;
 use log::{error, info};
-use shared::ServerToClientMessage;
-use std::collections::HashMap;
+use rand::Rng;
+use shared::{ChatMessage, HistoryCursor, ServerToClientMessage, MAX_MESSAGE_ID};
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
 
 pub enum ClientToServerEvent {
-    NewMessage(String),
-    ChatsFromTodayRequest,
+    NewMessage {
+        content: String,
+        idempotency_key: Option<String>,
+    },
+    HistoryRequest {
+        before: Option<HistoryCursor>,
+        limit: u32,
+    },
     Connect {
         user_id: i32,
         connection: SplitSink<WebSocket, Message>,
@@ -20,26 +33,61 @@ pub enum ClientToServerEvent {
     Disconnect {
         user_id: i32,
     },
+    /// Tells every connected client to reconnect to `url`, or to look the chatroom up again
+    /// when there is none, flushes their queues and stops the chatroom.
+    Close {
+        url: Option<String>,
+    },
 }
 
 pub struct Chatroom {
     chatroom_id: i32,
     channel: UnboundedSender<(i32, ClientToServerEvent)>,
+    // Counted as events are sent rather than handled, so a chatroom with a connection still
+    // in its queue is never considered empty.
     count: AtomicU32,
+    empty_since: Mutex<Instant>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    // The term of the chatroom and the region it was looked up in, once a client has sent them.
+    term: Mutex<Option<(String, Option<String>)>>,
+    // When the messages of the last activity window were sent, oldest first.
+    recent_messages: Mutex<VecDeque<Instant>>,
+    activity_window: Duration,
 }
 
 impl Chatroom {
-    pub fn new(model: Arc<Model>, chatroom_id: i32) -> Arc<Chatroom> {
+    pub fn new(
+        model: Arc<Model>,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+        backplane: Arc<dyn Backplane>,
+        chatroom_id: i32,
+    ) -> Arc<Chatroom> {
         let (sender, receiver) = unbounded_channel::<(i32, ClientToServerEvent)>();
+        let remote = backplane.subscribe(chatroom_id);
 
         let chatroom = Chatroom {
             chatroom_id,
             channel: sender,
             count: AtomicU32::new(0),
+            empty_since: Mutex::new(Instant::now()),
+            task: Mutex::new(None),
+            term: Mutex::new(None),
+            recent_messages: Mutex::new(VecDeque::new()),
+            activity_window: Duration::from_secs(config.activity_window_secs),
         };
 
         let chatroom = Arc::new(chatroom);
-        tokio::spawn(Self::handle_events(model, chatroom.clone(), receiver));
+        let task = tokio::spawn(Self::handle_events(
+            model,
+            config,
+            metrics,
+            backplane,
+            chatroom.clone(),
+            receiver,
+            remote,
+        ));
+        *chatroom.task.lock().unwrap() = Some(task);
         chatroom
     }
 
@@ -47,7 +95,66 @@ impl Chatroom {
         self.count.load(Ordering::SeqCst)
     }
 
+    /// Returns true if nobody has been connected to the chatroom for at least `timeout`.
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        self.get_user_count() == 0 && self.empty_since.lock().unwrap().elapsed() >= timeout
+    }
+
+    /// Returns the number of messages sent to the chatroom within the activity window,
+    /// including those sent on other instances.
+    pub fn get_recent_messages(&self) -> u32 {
+        let mut recent_messages = self.recent_messages.lock().unwrap();
+        self.forget_old_messages(&mut recent_messages);
+        recent_messages.len() as u32
+    }
+
+    fn record_message(&self) {
+        let mut recent_messages = self.recent_messages.lock().unwrap();
+        recent_messages.push_back(Instant::now());
+        self.forget_old_messages(&mut recent_messages);
+    }
+
+    fn forget_old_messages(&self, recent_messages: &mut VecDeque<Instant>) {
+        while let Some(sent) = recent_messages.front() {
+            if sent.elapsed() < self.activity_window {
+                break;
+            }
+            recent_messages.pop_front();
+        }
+    }
+
+    /// Returns the term of the chatroom and the region it was looked up in, if known.
+    pub fn get_term(&self) -> Option<(String, Option<String>)> {
+        self.term.lock().unwrap().clone()
+    }
+
+    pub fn set_term(&self, term: String, region: Option<String>) {
+        *self.term.lock().unwrap() = Some((term, region));
+    }
+
+    /// Stops the chatroom. Clients are told to reconnect to `url` if given. The returned task
+    /// finishes once every client has been told to reconnect and every pending write has
+    /// completed.
+    pub fn close(&self, url: Option<String>) -> Option<JoinHandle<()>> {
+        self.send_event(0, ClientToServerEvent::Close { url });
+        self.task.lock().unwrap().take()
+    }
+
     pub fn send_event(&self, user_id: i32, event: ClientToServerEvent) {
+        match &event {
+            ClientToServerEvent::Connect { .. } => {
+                self.count.fetch_add(1, Ordering::SeqCst);
+            }
+            ClientToServerEvent::Disconnect { .. } => {
+                let remaining = self.count.fetch_sub(1, Ordering::SeqCst) - 1;
+
+                if remaining == 0 {
+                    *self.empty_since.lock().unwrap() = Instant::now();
+                }
+            }
+            _ => {}
+        }
+
         let result = self.channel.send((user_id, event));
 
         if let Err(error) = result {
@@ -60,76 +167,223 @@ impl Chatroom {
 
     async fn handle_events(
         model: Arc<Model>,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+        backplane: Arc<dyn Backplane>,
         chatroom: Arc<Chatroom>,
         mut receiver: UnboundedReceiver<(i32, ClientToServerEvent)>,
+        mut remote: UnboundedReceiver<ServerToClientMessage>,
     ) {
         info!(
             "Started task to handle events for channel {}.",
             chatroom.chatroom_id
         );
 
-        let mut connections: HashMap<i32, SplitSink<WebSocket, Message>> = HashMap::new();
+        let mut connections: HashMap<i32, Connection> = HashMap::new();
+
+        // Idempotency keys of recent messages, mapped to the id of the message and when it was
+        // received.
+        let dedup_window = Duration::from_secs(config.dedup_window_secs);
+        let mut recent_keys: HashMap<String, (i64, Instant)> = HashMap::new();
 
         loop {
-            while let Some((user_id, event)) = receiver.recv().await {
-                match event {
-                    ClientToServerEvent::NewMessage(chat) => {
-                        let result = model.insert_chat(chatroom.chatroom_id, &chat).await;
+            let (user_id, event) = tokio::select! {
+                event = receiver.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                // Broadcast by the chatroom with the same id on another instance.
+                Some(message) = remote.recv() => {
+                    if let ServerToClientMessage::NewMessage { .. } = message {
+                        chatroom.record_message();
+                    }
+                    Self::broadcast_message(&connections, message);
+                    continue;
+                }
+            };
+
+            match event {
+                ClientToServerEvent::NewMessage {
+                    content,
+                    idempotency_key,
+                } => {
+                    if let Some(idempotency_key) = idempotency_key.as_ref() {
+                        let duplicate = Self::find_duplicate(
+                            &model,
+                            chatroom.chatroom_id,
+                            &recent_keys,
+                            dedup_window,
+                            idempotency_key,
+                        )
+                        .await;
+
+                        if let Some(message_id) = duplicate {
+                            info!(
+                                "Duplicate message from user {} acknowledged as {}.",
+                                user_id, message_id
+                            );
+
+                            let message = ServerToClientMessage::MessageAck {
+                                idempotency_key: idempotency_key.clone(),
+                                message_id,
+                            };
+                            Self::send_message(&connections, user_id, message);
+                            continue;
+                        }
+                    }
+
+                    let chat = ChatMessage {
+                        message_id: rand::thread_rng().gen_range(1..=MAX_MESSAGE_ID),
+                        user_id,
+                        timestamp: Utc::now().timestamp_millis(),
+                        content,
+                    };
+
+                    let result = model.insert_chat(chatroom.chatroom_id, &chat).await;
+
+                    if let Err(error) = result {
+                        error!("Failed to insert chat to database - {:?}", error);
+                    }
+
+                    let message_id = chat.message_id;
+                    chatroom.record_message();
+
+                    let message = ServerToClientMessage::NewMessage { message: chat };
+                    Self::publish(&backplane, chatroom.chatroom_id, &message);
+                    Self::broadcast_message(&connections, message);
+
+                    if let Some(idempotency_key) = idempotency_key {
+                        recent_keys.retain(|_key, (_message_id, received)| {
+                            received.elapsed() < dedup_window
+                        });
+                        recent_keys.insert(idempotency_key.clone(), (message_id, Instant::now()));
+
+                        let result = model
+                            .insert_idempotency_key(
+                                chatroom.chatroom_id,
+                                &idempotency_key,
+                                message_id,
+                                dedup_window,
+                            )
+                            .await;
 
                         if let Err(error) = result {
-                            error!("Failed to insert chat to database - {:?}", error);
+                            error!("Failed to insert idempotency key to database - {:?}", error);
                         }
 
-                        let message = ServerToClientMessage::NewMessage { content: chat };
-                        Self::broadcast_message(&mut connections, message).await;
+                        let message = ServerToClientMessage::MessageAck {
+                            idempotency_key,
+                            message_id,
+                        };
+                        Self::send_message(&connections, user_id, message);
                     }
+                }
 
-                    ClientToServerEvent::ChatsFromTodayRequest => {
-                        let result = model.get_chats_from_today(chatroom.chatroom_id).await;
-                        match result {
-                            Ok(messages) => {
-                                let message =
-                                    ServerToClientMessage::ChatsFromTodayResponse { messages };
-                                Self::send_message(&mut connections, user_id, message).await;
-                            }
-                            Err(error) => {
-                                error!("Failed to fetch chats from database - {:?}", error);
-                            }
+                ClientToServerEvent::HistoryRequest { before, limit } => {
+                    let result = model.get_history(chatroom.chatroom_id, before, limit).await;
+                    match result {
+                        Ok((messages, next_cursor)) => {
+                            let message = ServerToClientMessage::HistoryResponse {
+                                messages,
+                                next_cursor,
+                            };
+                            Self::send_message(&connections, user_id, message);
+                        }
+                        Err(error) => {
+                            error!("Failed to fetch chats from database - {:?}", error);
                         }
                     }
+                }
 
-                    ClientToServerEvent::Connect {
-                        user_id,
-                        connection,
-                    } => {
-                        chatroom.count.fetch_add(1, Ordering::SeqCst);
+                ClientToServerEvent::Connect {
+                    user_id,
+                    connection,
+                } => {
+                    let message = ServerToClientMessage::NewUser { user_id };
+                    Self::publish(&backplane, chatroom.chatroom_id, &message);
+                    Self::broadcast_message(&connections, message);
+
+                    let connection = Connection::new(connection, &config, metrics.clone());
+                    connections.insert(user_id, connection);
+
+                    let message = ServerToClientMessage::Joined {
+                        chatroom_id: chatroom.chatroom_id,
+                    };
+                    Self::send_message(&connections, user_id, message);
+                }
 
-                        let message = ServerToClientMessage::Joined {
-                            chatroom_id: chatroom.chatroom_id,
-                        };
-                        Self::send_message(&mut connections, user_id, message);
+                ClientToServerEvent::Disconnect { user_id } => {
+                    connections.remove(&user_id);
 
-                        let message = ServerToClientMessage::NewUser { user_id };
-                        Self::broadcast_message(&mut connections, message).await;
+                    let message = ServerToClientMessage::UserDisconnected { user_id };
+                    Self::publish(&backplane, chatroom.chatroom_id, &message);
+                    Self::broadcast_message(&connections, message);
+                }
 
-                        connections.insert(user_id, connection);
-                    }
+                ClientToServerEvent::Close { url } => {
+                    let message = ServerToClientMessage::Reconnect { url };
+                    Self::broadcast_message(&connections, message);
 
-                    ClientToServerEvent::Disconnect { user_id } => {
-                        chatroom.count.fetch_sub(1, Ordering::SeqCst);
-                        connections.remove(&user_id);
+                    let writers: Vec<JoinHandle<()>> = connections
+                        .drain()
+                        .filter_map(|(_user_id, connection)| connection.close())
+                        .collect();
+                    join_all(writers).await;
 
-                        let message = ServerToClientMessage::UserDisconnected { user_id };
-                        Self::broadcast_message(&mut connections, message).await;
-                    }
+                    break;
                 }
             }
         }
+
+        info!(
+            "Stopped task to handle events for channel {}.",
+            chatroom.chatroom_id
+        );
     }
 
-    #[async_recursion]
-    async fn send_message(
-        connections: &mut HashMap<i32, SplitSink<WebSocket, Message>>,
+    /// Returns the id of the message previously sent with the idempotency key, if any.
+    async fn find_duplicate(
+        model: &Model,
+        chatroom_id: i32,
+        recent_keys: &HashMap<String, (i64, Instant)>,
+        dedup_window: Duration,
+        idempotency_key: &str,
+    ) -> Option<i64> {
+        // Every key received here is kept in memory for the dedup window, so the store is only
+        // read for keys this chatroom hasn't seen. Those may have been recorded by another
+        // instance or before this instance was restarted.
+        if let Some((message_id, received)) = recent_keys.get(idempotency_key) {
+            return if received.elapsed() < dedup_window {
+                Some(*message_id)
+            } else {
+                None
+            };
+        }
+
+        match model
+            .get_idempotency_key(chatroom_id, idempotency_key)
+            .await
+        {
+            Ok(message_id) => message_id,
+            Err(error) => {
+                error!(
+                    "Failed to fetch idempotency key from database - {:?}",
+                    error
+                );
+                None
+            }
+        }
+    }
+
+    /// Shares the message with the chatrooms with the same id on other instances.
+    fn publish(backplane: &Arc<dyn Backplane>, chatroom_id: i32, message: &ServerToClientMessage) {
+        if backplane::is_shared(message) {
+            backplane.publish(chatroom_id, message);
+        }
+    }
+
+    fn send_message(
+        connections: &HashMap<i32, Connection>,
         user_id: i32,
         message: ServerToClientMessage,
     ) {
@@ -138,48 +392,23 @@ impl Chatroom {
         let message = serde_json::to_string(&message).unwrap();
         let message = Message::Text(message);
 
-        let connection = connections.get_mut(&user_id).unwrap();
-        let result = connection.send(message.clone()).await;
-
-        if result.is_err() {
-            connections.remove(&user_id);
-
-            Self::broadcast_message(
-                connections,
-                ServerToClientMessage::UserDisconnected { user_id },
-            )
-            .await;
+        // The user may have disconnected before the message was ready. A connection that
+        // fails to take the message has been closed, and its reader reports the disconnect.
+        if let Some(connection) = connections.get(&user_id) {
+            connection.send(message);
         }
     }
 
-    #[async_recursion]
-    async fn broadcast_message(
-        connections: &mut HashMap<i32, SplitSink<WebSocket, Message>>,
-        message: ServerToClientMessage,
-    ) {
+    fn broadcast_message(connections: &HashMap<i32, Connection>, message: ServerToClientMessage) {
         info!("Broadcasting message - {:?}", message);
 
         let message = serde_json::to_string(&message).unwrap();
         let message = Message::Text(message);
 
-        let mut disconnected = Vec::new();
-
-        for (id, connection) in connections.into_iter() {
-            let result = connection.send(message.clone()).await;
-
-            if result.is_err() {
-                disconnected.push(*id);
-            }
-        }
-
-        for user_id in disconnected {
-            connections.remove(&user_id);
-
-            Self::broadcast_message(
-                connections,
-                ServerToClientMessage::UserDisconnected { user_id },
-            )
-            .await;
+        // Connections closed by an overflow are left to their reader, which reports the
+        // disconnect once the socket has closed.
+        for connection in connections.values() {
+            connection.send(message.clone());
         }
     }
 }
@@ -0,0 +1,75 @@
+use crate::backplane::BackplaneKind;
+use crate::connection::OverflowPolicy;
+use serde::Deserialize;
+use shared::config::Storage;
+use shared::migration::Replication;
+use std::net::{SocketAddr, SocketAddrV4};
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The address the server listens on.
+    pub bind_address: SocketAddr,
+    /// The address this instance is registered under. Clients connect to it directly, so it
+    /// must be reachable from outside the cluster.
+    pub address: Option<SocketAddrV4>,
+    pub discovery_url: String,
+    /// The region this instance runs in. Discovery prefers instances in the region of the
+    /// client.
+    pub region: String,
+    /// How often this instance pings the discovery service to keep its registration alive.
+    pub heartbeat_interval_secs: u64,
+    /// How long the idempotency key of a message is remembered. A message resent with the same
+    /// key within this window is acknowledged but not delivered again.
+    pub dedup_window_secs: u64,
+    /// How many messages may be waiting to be written to a single client.
+    pub outbound_queue_size: usize,
+    /// What to do when a client falls so far behind that its queue is full.
+    pub overflow_policy: OverflowPolicy,
+    /// How long a chatroom may stay empty before it is closed.
+    pub room_idle_timeout_secs: u64,
+    /// How long to wait for clients to be flushed and told to reconnect on shutdown.
+    pub shutdown_timeout_secs: u64,
+    /// How far back messages count towards the activity of a chatroom, which the frontend
+    /// server ranks chatrooms by.
+    pub activity_window_secs: u64,
+    pub storage: Storage,
+    /// Whitespace separated list of ScyllaDB nodes.
+    pub scylla_url: Option<String>,
+    /// The replication of the keyspace. Changing it alters the keyspace on the next migration.
+    pub replication: Replication,
+    /// How chatrooms share messages with the chatrooms of the same term on other instances.
+    pub backplane: BackplaneKind,
+    /// The address the TCP backplane listens on for messages from peers.
+    pub backplane_bind_address: SocketAddr,
+    /// The backplane addresses of the other chatroom instances, when using the TCP backplane.
+    pub backplane_peers: Vec<SocketAddr>,
+    /// Whether to apply schema migrations on startup. When disabled they are applied by
+    /// running the service with the migrate subcommand.
+    pub migrate_on_startup: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: "0.0.0.0:3000".parse().unwrap(),
+            address: None,
+            discovery_url: "http://discovery.gerber.website:8081".to_string(),
+            region: "US1".to_string(),
+            heartbeat_interval_secs: 2,
+            dedup_window_secs: 300,
+            outbound_queue_size: 256,
+            overflow_policy: OverflowPolicy::DropOldest,
+            room_idle_timeout_secs: 300,
+            shutdown_timeout_secs: 10,
+            activity_window_secs: 600,
+            storage: Storage::Scylla,
+            scylla_url: None,
+            replication: Replication::default(),
+            migrate_on_startup: true,
+            backplane: BackplaneKind::Local,
+            backplane_bind_address: "0.0.0.0:3001".parse().unwrap(),
+            backplane_peers: Vec::new(),
+        }
+    }
+}
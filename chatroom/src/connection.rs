@@ -0,0 +1,244 @@
+use crate::config::Config;
+use crate::metrics::Metrics;
+use axum::extract::ws::{CloseFrame, Message};
+use futures::{Sink, SinkExt};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// What happens to a message sent to a connection whose queue is full.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room.
+    DropOldest,
+    /// Close the connection. The client is told why in the close frame.
+    Disconnect,
+}
+
+// Sent as the close code when the server is shutting down.
+const GOING_AWAY: u16 = 1001;
+// Sent as the close code when a connection is dropped because its queue overflowed.
+const POLICY_VIOLATION: u16 = 1008;
+
+struct Queue {
+    messages: Mutex<VecDeque<Message>>,
+    notify: Notify,
+    closed: AtomicBool,
+}
+
+impl Queue {
+    /// Stops the writer once `frame` has been sent. Messages that are already queued are sent
+    /// first unless `discard` is set.
+    fn close(&self, frame: Option<CloseFrame<'static>>, discard: bool) {
+        let mut messages = self.messages.lock().unwrap();
+
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if discard {
+            messages.clear();
+        }
+
+        if let Some(frame) = frame {
+            messages.push_back(Message::Close(Some(frame)));
+        }
+
+        self.notify.notify_one();
+    }
+}
+
+/// The sending half of a websocket. Messages are queued and written by a dedicated task, so a
+/// slow client never blocks the chatroom that is sending to it.
+pub struct Connection {
+    queue: Arc<Queue>,
+    writer: Option<JoinHandle<()>>,
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl Connection {
+    pub fn new<S>(sink: S, config: &Config, metrics: Arc<Metrics>) -> Connection
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+    {
+        let queue = Arc::new(Queue {
+            messages: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+        });
+
+        let writer = tokio::spawn(Self::write_messages(queue.clone(), sink));
+
+        Connection {
+            queue,
+            writer: Some(writer),
+            capacity: config.outbound_queue_size,
+            overflow_policy: config.overflow_policy,
+            metrics,
+        }
+    }
+
+    /// Queues a message without waiting for it to be written. Returns false if the connection
+    /// has been closed, either because the client went away or because the queue overflowed.
+    pub fn send(&self, message: Message) -> bool {
+        let mut messages = self.queue.messages.lock().unwrap();
+
+        if self.queue.closed.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        if messages.len() >= self.capacity {
+            match self.overflow_policy {
+                OverflowPolicy::DropOldest => {
+                    messages.pop_front();
+                    self.metrics
+                        .dropped_messages
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::Disconnect => {
+                    drop(messages);
+                    self.metrics
+                        .overflow_disconnects
+                        .fetch_add(1, Ordering::Relaxed);
+
+                    self.queue.close(
+                        Some(CloseFrame {
+                            code: POLICY_VIOLATION,
+                            reason: "Too many undelivered messages.".into(),
+                        }),
+                        true,
+                    );
+                    return false;
+                }
+            }
+        }
+
+        messages.push_back(message);
+        self.queue.notify.notify_one();
+        true
+    }
+
+    /// Closes the connection after every queued message has been written. The returned task
+    /// finishes once the client has been sent a close frame.
+    pub fn close(mut self) -> Option<JoinHandle<()>> {
+        self.queue.close(
+            Some(CloseFrame {
+                code: GOING_AWAY,
+                reason: "Server is shutting down.".into(),
+            }),
+            false,
+        );
+
+        self.writer.take()
+    }
+
+    async fn write_messages<S>(queue: Arc<Queue>, mut sink: S)
+    where
+        S: Sink<Message> + Unpin,
+    {
+        loop {
+            let message = queue.messages.lock().unwrap().pop_front();
+
+            match message {
+                Some(message) => {
+                    if sink.send(message).await.is_err() {
+                        queue.close(None, true);
+                        break;
+                    }
+                }
+                None => {
+                    if queue.closed.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    queue.notify.notified().await;
+                }
+            }
+        }
+
+        let _ = sink.close().await;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.queue.close(None, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::connection::{Connection, OverflowPolicy};
+    use crate::metrics::Metrics;
+    use axum::extract::ws::Message;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    fn connection(overflow_policy: OverflowPolicy) -> (Connection, Arc<Metrics>) {
+        let config = Config {
+            outbound_queue_size: 2,
+            overflow_policy,
+            ..Config::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+
+        // Nothing reads from the channel, so the writer stalls like a slow client would.
+        let (sink, receiver) = mpsc::channel::<Message>(0);
+        std::mem::forget(receiver);
+
+        let connection = Connection::new(sink, &config, metrics.clone());
+        (connection, metrics)
+    }
+
+    #[tokio::test]
+    async fn oldest_messages_are_dropped() {
+        let (connection, metrics) = connection(OverflowPolicy::DropOldest);
+
+        for i in 0..10 {
+            assert!(connection.send(Message::Text(i.to_string())));
+            tokio::task::yield_now().await;
+        }
+
+        assert!(metrics.dropped_messages.load(Ordering::Relaxed) > 0);
+        assert_eq!(metrics.overflow_disconnects.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_flushed_on_close() {
+        let config = Config::default();
+        let metrics = Arc::new(Metrics::default());
+        let (sink, receiver) = mpsc::unbounded::<Message>();
+
+        let connection = Connection::new(sink, &config, metrics);
+        for i in 0..3 {
+            assert!(connection.send(Message::Text(i.to_string())));
+        }
+        connection.close().unwrap().await.unwrap();
+
+        let messages: Vec<Message> = receiver.collect().await;
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(messages.last(), Some(Message::Close(Some(_)))));
+    }
+
+    #[tokio::test]
+    async fn slow_clients_are_disconnected() {
+        let (connection, metrics) = connection(OverflowPolicy::Disconnect);
+
+        let sent = (0..10)
+            .take_while(|i| connection.send(Message::Text(i.to_string())))
+            .count();
+
+        assert!(sent < 10);
+        assert!(!connection.send(Message::Text("late".to_string())));
+        assert_eq!(metrics.overflow_disconnects.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,41 +1,72 @@
-#![feature(try_blocks)]
-
 // This is a basic implementation for a server that can handle an arbitrary number of chatrooms.
 // All chatrooms are assigned a unique id by external services and the associated chatroom is
 // allocated when the first client connects to the server.
 
+mod backplane;
 mod chatroom;
+mod config;
+mod connection;
+mod metrics;
 mod model;
+mod store;
 
+use crate::backplane::{open_backplane, Backplane};
 use crate::chatroom::{Chatroom, ClientToServerEvent};
+use crate::config::Config;
+use crate::metrics::Metrics;
 use crate::model::Model;
 use axum::{
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    http::StatusCode,
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
-use futures::StreamExt;
+use futures::future::join_all;
+use futures::stream::SplitSink;
+use futures::{SinkExt, StreamExt};
 use log::{error, info};
-use shared::discovery::{PingRequest, PingResponse, PingResult, RegisterRequest, RegisterResponse};
-use shared::{get_channel_id, initialize_logger, ClientToServerMessage};
+use shared::discovery::{
+    BatchChatroomRequest, BatchChatroomResponse, ChatroomRequest, ChatroomResponse,
+    DeregisterRequest, Lease, Load, PingRequest, PingResponse, PingResult, RegisterRequest,
+    RegisterResponse,
+};
+use shared::{
+    get_channel_id, initialize_logger, ChatroomActivity, ClientToServerMessage,
+    ServerToClientMessage,
+};
 use std::collections::HashMap;
-use std::env;
 use std::error::Error;
 use std::net::SocketAddrV4;
-use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use sysinfo::{ProcessorExt, RefreshKind, System, SystemExt};
 use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::spawn;
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 
 type BoxError = Box<dyn Error + Send + Sync>;
 type BoxResult<T> = Result<T, BoxError>;
 
 struct State {
     model: Arc<Model>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    backplane: Arc<dyn Backplane>,
     chatrooms: HashMap<i32, Arc<Chatroom>>,
+    // Set once the instance starts draining. No new chatrooms are opened after that.
+    draining: bool,
+    // The lease granted by discovery and when it runs out. Joins are refused without one, since
+    // discovery may have moved our terms to another instance.
+    lease: Option<(Lease, Instant)>,
+    // Set once registered with discovery.
+    instance_id: Option<i32>,
+    // The address this instance is registered under.
+    address: SocketAddrV4,
+    heartbeat: Option<JoinHandle<()>>,
+    client: reqwest::Client,
 }
 
 impl State {
@@ -47,29 +78,202 @@ impl State {
         } else {
             info!("New channel requested.");
 
-            let chatroom = Chatroom::new(self.model.clone(), chatroom_id);
+            let chatroom = Chatroom::new(
+                self.model.clone(),
+                self.config.clone(),
+                self.metrics.clone(),
+                self.backplane.clone(),
+                chatroom_id,
+            );
             self.chatrooms.insert(chatroom_id, chatroom.clone());
             chatroom
         }
     }
+
+    /// Returns the load reported to discovery, leaving the CPU usage to the caller.
+    fn get_load(&self) -> Load {
+        Load {
+            chatrooms: self.chatrooms.len() as u32,
+            users: self
+                .chatrooms
+                .values()
+                .map(|chatroom| chatroom.get_user_count())
+                .sum(),
+            cpu: 0.0,
+        }
+    }
+
+    fn get_activity(&self, chatroom_id: i32) -> ChatroomActivity {
+        let chatroom = self.chatrooms.get(&chatroom_id);
+
+        ChatroomActivity {
+            chatroom_id,
+            num_users: chatroom.map_or(0, |chatroom| chatroom.get_user_count()),
+            recent_messages: chatroom.map_or(0, |chatroom| chatroom.get_recent_messages()),
+        }
+    }
+
+    /// Closes every chatroom that has been empty for longer than the idle timeout.
+    fn reap_idle_chatrooms(&mut self) {
+        let timeout = Duration::from_secs(self.config.room_idle_timeout_secs);
+
+        self.chatrooms.retain(|chatroom_id, chatroom| {
+            if chatroom.is_idle(timeout) {
+                info!("Closing idle chatroom {}.", chatroom_id);
+                chatroom.close(None);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn holds_lease(&self) -> bool {
+        matches!(self.lease, Some((_lease, expires_at)) if Instant::now() < expires_at)
+    }
+
+    /// Records a lease granted or renewed by a request sent at `sent_at`. Renewals of an older
+    /// epoch are ignored.
+    fn renew_lease(&mut self, lease: Lease, sent_at: Instant) {
+        if let Some((current, _expires_at)) = self.lease {
+            if lease.epoch < current.epoch {
+                return;
+            }
+        }
+
+        let expires_at = sent_at + Duration::from_millis(lease.duration_ms);
+        self.lease = Some((lease, expires_at));
+    }
+}
+
+/// Moves every chatroom off of this instance and stops new ones from being opened. Discovery is
+/// told that we are leaving so that it reassigns our terms, then the clients of each chatroom
+/// are told to reconnect to the instance its term maps to now. Clients of chatrooms whose term
+/// isn't known look them up again. Does nothing if the instance is already draining.
+async fn drain(state: &Arc<RwLock<State>>) {
+    let mut guard = state.write().await;
+    if guard.draining {
+        return;
+    }
+
+    info!("Draining {} chatrooms.", guard.chatrooms.len());
+    guard.draining = true;
+
+    // A ping after deregistering would find the registration gone and exit.
+    if let Some(heartbeat) = guard.heartbeat.take() {
+        heartbeat.abort();
+    }
+
+    let chatrooms: Vec<Arc<Chatroom>> = guard
+        .chatrooms
+        .drain()
+        .map(|(_chatroom_id, chatroom)| chatroom)
+        .collect();
+    let client = guard.client.clone();
+    let config = guard.config.clone();
+    let address = guard.address;
+    let instance_id = guard.instance_id;
+    drop(guard);
+
+    if let Some(instance_id) = instance_id {
+        let result = client
+            .post(format!("{}/deregister", config.discovery_url))
+            .json(&DeregisterRequest {
+                address,
+                region: Some(config.region.clone()),
+                instance_id,
+            })
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        if let Err(error) = result {
+            error!(
+                "Failed to deregister from the discovery service, our terms will be reassigned \
+                 once the registration expires - {:?}",
+                error
+            );
+        }
+    }
+
+    let mut urls = reassign_chatrooms(&client, &config, instance_id, &chatrooms).await;
+
+    let closed: Vec<JoinHandle<()>> = chatrooms
+        .iter()
+        .filter_map(|chatroom| {
+            let url = chatroom
+                .get_term()
+                .and_then(|(term, _region)| urls.remove(&term));
+            chatroom.close(url)
+        })
+        .collect();
+
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+
+    if tokio::time::timeout(shutdown_timeout, join_all(closed))
+        .await
+        .is_err()
+    {
+        error!("Timed out waiting for chatrooms to drain.");
+    }
+}
+
+/// Asks discovery for the instance each chatroom is hosted by now, grouped by the region the
+/// chatrooms were looked up in. Returns the url to join each term at.
+async fn reassign_chatrooms(
+    client: &reqwest::Client,
+    config: &Config,
+    instance_id: Option<i32>,
+    chatrooms: &[Arc<Chatroom>],
+) -> HashMap<String, String> {
+    let mut regions: HashMap<Option<String>, Vec<String>> = HashMap::new();
+    for (term, region) in chatrooms.iter().filter_map(|chatroom| chatroom.get_term()) {
+        regions.entry(region).or_default().push(term);
+    }
+
+    let mut urls = HashMap::new();
+
+    for (region, terms) in regions {
+        let request = BatchChatroomRequest { terms, region };
+        let response = lookup_chatrooms(client, &config.discovery_url, &request).await;
+
+        match response {
+            Ok(response) => {
+                for (term, instance) in response.instances {
+                    // Only possible if deregistering failed.
+                    if Some(instance.instance_id) != instance_id {
+                        urls.insert(term, format!("ws://{}/ws", instance.address));
+                    }
+                }
+            }
+            Err(error) => {
+                error!("Failed to reassign chatrooms - {:?}", error);
+            }
+        }
+    }
+
+    urls
+}
+
+async fn drain_handler(state: Arc<RwLock<State>>) -> StatusCode {
+    drain(&state).await;
+    StatusCode::OK
 }
 
 async fn chatrooms_handler(
     state: Arc<RwLock<State>>,
     Json(terms): Json<Vec<String>>,
-) -> Json<HashMap<String, (i32, u32)>> {
-    let mut counts = HashMap::new();
+) -> Json<HashMap<String, ChatroomActivity>> {
+    let mut activity = HashMap::new();
+
+    let state = state.read().await;
 
     for term in terms {
         let channel_id = get_channel_id(&term);
-
-        let mut state = state.write().await;
-        let channel = state.get_channel(channel_id).await;
-
-        counts.insert(term, (channel_id, channel.get_user_count()));
+        activity.insert(term, state.get_activity(channel_id));
     }
 
-    Json(counts)
+    Json(activity)
 }
 
 async fn ws_handler(state: Arc<RwLock<State>>, ws: WebSocketUpgrade) -> impl IntoResponse {
@@ -83,6 +287,64 @@ fn parse_message(message: Message) -> Option<ClientToServerMessage> {
     }
 }
 
+/// Tells a client that can't join the chatroom on this instance where to look instead, then
+/// closes the connection.
+async fn reject_join(mut sink: SplitSink<WebSocket, Message>, message: ServerToClientMessage) {
+    let message = serde_json::to_string(&message).unwrap();
+
+    let _ = sink.send(Message::Text(message)).await;
+    let _ = sink.close().await;
+}
+
+/// Asks discovery which instance hosts the term for clients in the region. Returns the url to
+/// redirect the client to when it is another instance. If discovery can't be reached the term
+/// is served here, as the lease already stops us from serving terms we may have lost.
+async fn find_owner(
+    state: &Arc<RwLock<State>>,
+    term: &str,
+    region: Option<String>,
+) -> Option<String> {
+    let guard = state.read().await;
+    let client = guard.client.clone();
+    let discovery_url = guard.config.discovery_url.clone();
+    let instance_id = guard.instance_id;
+    drop(guard);
+
+    let request = ChatroomRequest {
+        term: term.to_string(),
+        region,
+    };
+    let response = lookup_chatroom(&client, &discovery_url, &request).await;
+
+    match response {
+        Ok(ChatroomResponse {
+            instance: Some(instance),
+        }) if Some(instance.instance_id) != instance_id => {
+            Some(format!("ws://{}/ws", instance.address))
+        }
+        Ok(_response) => None,
+        Err(error) => {
+            error!(
+                "Failed to check which instance hosts term {} - {:?}",
+                term, error
+            );
+            None
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Failed to install the SIGTERM handler.");
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    info!("Shutdown signal received.");
+}
+
 async fn handle_socket_messages(state: Arc<RwLock<State>>, socket: WebSocket) {
     let (sink, mut stream) = socket.split();
 
@@ -96,13 +358,49 @@ async fn handle_socket_messages(state: Arc<RwLock<State>>, socket: WebSocket) {
             let message = parse_message(message);
             if let Some(ClientToServerMessage::Join {
                 chatroom_id: channel_id,
+                term,
+                region,
             }) = message
             {
-                let chatroom = {
-                    let mut state = state.write().await;
-                    state.get_channel(channel_id).await
-                };
+                // Clients that don't send the term are trusted to have looked it up.
+                if let Some(term) = &term {
+                    if get_channel_id(term) != channel_id {
+                        error!(
+                            "User {} sent a term that doesn't match the chatroom.",
+                            user_id
+                        );
+                        return;
+                    }
+
+                    if let Some(url) = find_owner(&state, term, region.clone()).await {
+                        info!("Redirecting user {} to {}.", user_id, url);
+                        reject_join(sink, ServerToClientMessage::Redirect { url }).await;
+                        return;
+                    }
+                }
 
+                let mut guard = state.write().await;
+
+                if guard.draining {
+                    drop(guard);
+                    reject_join(sink, ServerToClientMessage::Reconnect { url: None }).await;
+                    return;
+                }
+
+                if !guard.holds_lease() {
+                    drop(guard);
+                    info!("Rejecting user {} since our lease has expired.", user_id);
+                    reject_join(sink, ServerToClientMessage::Moved).await;
+                    return;
+                }
+
+                // The connection is queued while the state is locked so that the chatroom
+                // can't be reaped before the user is counted.
+                let chatroom = guard.get_channel(channel_id).await;
+                if let Some(term) = term {
+                    // Remembered so that the chatroom can be moved when the instance drains.
+                    chatroom.set_term(term, region);
+                }
                 chatroom.send_event(
                     user_id,
                     ClientToServerEvent::Connect {
@@ -110,6 +408,7 @@ async fn handle_socket_messages(state: Arc<RwLock<State>>, socket: WebSocket) {
                         connection: sink,
                     },
                 );
+                drop(guard);
 
                 info!("User {} joined a server.", user_id);
 
@@ -123,14 +422,22 @@ async fn handle_socket_messages(state: Arc<RwLock<State>>, socket: WebSocket) {
                             ClientToServerMessage::Join { .. } => {
                                 // Joining is unsupported once in a chatroom.
                             }
-                            ClientToServerMessage::NewMessage { content } => {
-                                chatroom
-                                    .send_event(user_id, ClientToServerEvent::NewMessage(content));
+                            ClientToServerMessage::NewMessage {
+                                content,
+                                idempotency_key,
+                            } => {
+                                chatroom.send_event(
+                                    user_id,
+                                    ClientToServerEvent::NewMessage {
+                                        content,
+                                        idempotency_key,
+                                    },
+                                );
                             }
-                            ClientToServerMessage::ChatsFromTodayRequest => {
+                            ClientToServerMessage::HistoryRequest { before, limit } => {
                                 chatroom.send_event(
                                     user_id,
-                                    ClientToServerEvent::ChatsFromTodayRequest,
+                                    ClientToServerEvent::HistoryRequest { before, limit },
                                 );
                             }
                         }
@@ -150,66 +457,133 @@ fn main() -> BoxResult<()> {
     initialize_logger()?;
     dotenv::dotenv().ok();
 
+    let config: Config = shared::config::load()?;
+
     let runtime = Runtime::new()?;
-    runtime.block_on(async_main())?;
+    match shared::config::subcommand().as_deref() {
+        None => runtime.block_on(async_main(config))?,
+        Some("migrate") => runtime.block_on(store::migrate(&config))?,
+        Some(command) => return Err(format!("Unknown command {}.", command).into()),
+    }
     Ok(())
 }
 
-async fn async_main() -> BoxResult<()> {
+/// Registers this instance with discovery and records the lease it was granted. Returns the
+/// instance id and the epoch of the lease, which every ping must carry.
+async fn register(
+    state: &Arc<RwLock<State>>,
+    client: &reqwest::Client,
+    config: &Config,
+    address: SocketAddrV4,
+) -> BoxResult<(i32, i64)> {
+    let sent_at = Instant::now();
+    let registration = client
+        .post(format!("{}/register", config.discovery_url))
+        .json(&RegisterRequest {
+            address,
+            region: Some(config.region.clone()),
+        })
+        .send()
+        .await?
+        .json::<RegisterResponse>()
+        .await?;
+
+    let mut guard = state.write().await;
+    guard.instance_id = Some(registration.instance_id);
+    guard.renew_lease(registration.lease, sent_at);
+
+    Ok((registration.instance_id, registration.lease.epoch))
+}
+
+async fn async_main(config: Config) -> BoxResult<()> {
+    let address = config.address.ok_or("ADDRESS not defined.")?;
+    let config = Arc::new(config);
+    let metrics = Arc::new(Metrics::default());
+
+    let client = reqwest::Client::new();
+
     let chatrooms = Arc::new(RwLock::new(State {
-        model: Arc::new(Model::new().await?),
+        model: Arc::new(Model::new(&config).await?),
+        config: config.clone(),
+        metrics: metrics.clone(),
+        backplane: open_backplane(&config).await?,
         chatrooms: HashMap::new(),
+        draining: false,
+        lease: None,
+        instance_id: None,
+        address,
+        heartbeat: None,
+        client: client.clone(),
     }));
 
     let ws_state = chatrooms.clone();
     let chatrooms_state = chatrooms.clone();
+    let drain_state = chatrooms.clone();
 
     let app = Router::new()
         .route("/ws", get(move |ws| ws_handler(ws_state, ws)))
         .route(
             "/chatrooms",
             post(move |terms| chatrooms_handler(chatrooms_state, terms)),
-        );
-
-    tokio::spawn(async {
-        let address = env::var("ADDRESS").expect("ADDRESS not defined.");
-        let address =
-            SocketAddrV4::from_str(&address).expect("ADDRESS is not valid socket address");
-
-        let client = reqwest::Client::new();
-        let registration = client
-            // .post("http://localhost:8081/register")
-            .post("http://discovery.gerber.website:8081/register")
-            .json(&RegisterRequest { address })
-            .send()
-            .await
-            .expect("Failed to contact discovery service.")
-            .json::<RegisterResponse>()
-            .await
-            .expect("Invalid response from discovery service.");
+        )
+        .route("/drain", post(move || drain_handler(drain_state)))
+        .route("/metrics", get(move || async move { metrics.render() }));
+
+    // Bind before registering so that discovery never hands out an address nobody listens on.
+    let server = axum::Server::bind(&config.bind_address).serve(app.into_make_service());
+
+    let (mut instance_id, mut epoch) = register(&chatrooms, &client, &config, address).await?;
+
+    let region = config.region.clone();
+    let heartbeat_client = client.clone();
+    let heartbeat_state = chatrooms.clone();
+    let heartbeat_config = config.clone();
+    let discovery_url = config.discovery_url.clone();
+    let heartbeat_interval = Duration::from_secs(config.heartbeat_interval_secs);
+
+    let heartbeat = tokio::spawn(async move {
+        let client = heartbeat_client;
+        let mut system = System::new_with_specifics(RefreshKind::new().with_cpu());
 
         loop {
-            tokio::time::sleep(Duration::from_secs(2)).await;
-
-            let response: BoxResult<PingResponse> = try {
-                client
-                    // .post("http://localhost:8081/ping")
-                    .post("http://discovery.gerber.website:8081/ping")
-                    .json(&PingRequest {
-                        address,
-                        instance_id: registration.instance_id,
-                    })
-                    .send()
-                    .await?
-                    .json::<PingResponse>()
-                    .await?
+            tokio::time::sleep(heartbeat_interval).await;
+
+            // CPU usage is measured over the time since the previous refresh.
+            system.refresh_cpu();
+            let load = Load {
+                cpu: system.global_processor_info().cpu_usage(),
+                ..heartbeat_state.read().await.get_load()
+            };
+
+            let request = PingRequest {
+                address,
+                region: Some(region.clone()),
+                instance_id,
+                load,
+                epoch,
             };
+            let sent_at = Instant::now();
+            let response = ping(&client, &discovery_url, &request).await;
 
             match response {
                 Ok(response) => {
+                    // The registration expired or was replaced, so the lease can't be renewed.
+                    // Joins are refused until registering again grants a new one.
                     if let PingResult::NoLongerActive = response.ping_result {
-                        error!("Registration expired. Exiting.");
-                        std::process::exit(-1);
+                        error!("Registration expired. Registering again.");
+
+                        match register(&heartbeat_state, &client, &heartbeat_config, address).await
+                        {
+                            Ok(registration) => (instance_id, epoch) = registration,
+                            Err(error) => {
+                                error!("Failed to register again - {:?}", error);
+                            }
+                        }
+                        continue;
+                    }
+
+                    if let Some(lease) = response.lease {
+                        heartbeat_state.write().await.renew_lease(lease, sent_at);
                     }
                 }
                 Err(error) => {
@@ -221,11 +595,78 @@ async fn async_main() -> BoxResult<()> {
             }
         }
     });
+    chatrooms.write().await.heartbeat = Some(heartbeat);
 
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let reaper_state = chatrooms.clone();
+    // Sweeping several times per timeout closes an idle chatroom soon after it times out,
+    // rather than up to a whole timeout later.
+    let reap_interval =
+        (Duration::from_secs(config.room_idle_timeout_secs) / 4).max(Duration::from_secs(1));
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(reap_interval).await;
+            reaper_state.write().await.reap_idle_chatrooms();
+        }
+    });
+
+    server.with_graceful_shutdown(shutdown_signal()).await?;
+
+    // Move everyone off of this instance, unless it was already drained through /drain.
+    drain(&chatrooms).await;
 
     Ok(())
 }
+
+/// Reports the load of this instance to the discovery service.
+async fn ping(
+    client: &reqwest::Client,
+    discovery_url: &str,
+    request: &PingRequest,
+) -> BoxResult<PingResponse> {
+    let response = client
+        .post(format!("{}/ping", discovery_url))
+        .json(request)
+        .send()
+        .await?
+        .json::<PingResponse>()
+        .await?;
+
+    Ok(response)
+}
+
+/// Asks the discovery service which instance hosts the chatroom of a term.
+async fn lookup_chatroom(
+    client: &reqwest::Client,
+    discovery_url: &str,
+    request: &ChatroomRequest,
+) -> BoxResult<ChatroomResponse> {
+    let response = client
+        .post(format!("{}/chatroom", discovery_url))
+        .json(request)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ChatroomResponse>()
+        .await?;
+
+    Ok(response)
+}
+
+/// Asks the discovery service which instances host the chatrooms of several terms.
+async fn lookup_chatrooms(
+    client: &reqwest::Client,
+    discovery_url: &str,
+    request: &BatchChatroomRequest,
+) -> BoxResult<BatchChatroomResponse> {
+    let response = client
+        .post(format!("{}/chatrooms/batch", discovery_url))
+        .json(request)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<BatchChatroomResponse>()
+        .await?;
+
+    Ok(response)
+}
@@ -0,0 +1,38 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters exported on the /metrics route in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// Messages discarded because a connection's queue was full.
+    pub dropped_messages: AtomicU64,
+    /// Connections closed because their queue was full.
+    pub overflow_disconnects: AtomicU64,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "chatroom_dropped_messages_total",
+                "Messages discarded because a connection's queue was full.",
+                &self.dropped_messages,
+            ),
+            (
+                "chatroom_overflow_disconnects_total",
+                "Connections closed because their queue was full.",
+                &self.overflow_disconnects,
+            ),
+        ];
+
+        let mut output = String::new();
+
+        for (name, help, value) in counters {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            let _ = writeln!(output, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        output
+    }
+}
@@ -1,100 +1,121 @@
+use crate::config::Config;
+use crate::store::{open_store, Store};
 use crate::BoxResult;
-use chrono::Local;
-use log::error;
-use scylla::{IntoTypedRows, Session, SessionBuilder};
-use std::env;
+use shared::{ChatMessage, HistoryCursor};
+use std::time::Duration;
+
+/// The largest page of history a client may request.
+const MAX_HISTORY_LIMIT: u32 = 200;
 
 pub struct Model {
-    session: Session,
+    store: Box<dyn Store>,
 }
 
 impl Model {
-    pub async fn new() -> BoxResult<Self> {
-        let scylla_urls = env::var("SCYLLA_URL")?;
-        let scylla_urls: Vec<&str> = scylla_urls.split_whitespace().collect();
-
-        // Generate all tables in the database.
-        let session = SessionBuilder::new()
-            .known_nodes(&scylla_urls)
-            .build()
-            .await?;
+    pub async fn new(config: &Config) -> BoxResult<Self> {
+        let store = open_store(config).await?;
+        Ok(Self::with_store(store))
+    }
 
-        session
-            .query(
-                r#"
-                CREATE KEYSPACE IF NOT EXISTS searchbuddy
-                WITH REPLICATION = {
-                    'class': 'SimpleStrategy',
-                    'replication_factor': 1
-                };
-                "#,
-                (),
-            )
-            .await?;
+    pub fn with_store(store: Box<dyn Store>) -> Self {
+        Model { store }
+    }
 
-        session.use_keyspace("searchbuddy", false).await?;
-
-        session
-            .query(
-                r#"
-                CREATE TABLE IF NOT EXISTS chat (
-                    chatroom_id int,
-                    ts timestamp,
-                    content text,
-                    PRIMARY KEY(chatroom_id, ts),
-                );
-                "#,
-                (),
-            )
-            .await?;
+    pub async fn insert_chat(&self, chatroom_id: i32, message: &ChatMessage) -> BoxResult<()> {
+        self.store.insert_chat(chatroom_id, message).await
+    }
+
+    pub async fn insert_idempotency_key(
+        &self,
+        chatroom_id: i32,
+        idempotency_key: &str,
+        message_id: i64,
+        ttl: Duration,
+    ) -> BoxResult<()> {
+        self.store
+            .insert_idempotency_key(chatroom_id, idempotency_key, message_id, ttl.as_secs())
+            .await
+    }
 
-        Ok(Model { session })
+    pub async fn get_idempotency_key(
+        &self,
+        chatroom_id: i32,
+        idempotency_key: &str,
+    ) -> BoxResult<Option<i64>> {
+        self.store
+            .get_idempotency_key(chatroom_id, idempotency_key)
+            .await
     }
 
-    pub async fn insert_chat(&self, chatroom_id: i32, content: &str) -> BoxResult<()> {
-        let now = Local::now();
-
-        self.session
-            .query(
-                r#"
-                INSERT INTO chat (chatroom_id, ts, content)
-                VALUES (?, ?, ?);
-                "#,
-                (&chatroom_id, now.timestamp_millis(), content),
-            )
+    /// Returns a page of chats sent before the cursor and the cursor of the next, older page.
+    pub async fn get_history(
+        &self,
+        chatroom_id: i32,
+        before: Option<HistoryCursor>,
+        limit: u32,
+    ) -> BoxResult<(Vec<ChatMessage>, Option<HistoryCursor>)> {
+        let limit = limit.min(MAX_HISTORY_LIMIT);
+        let messages = self
+            .store
+            .get_chats_before(chatroom_id, before, limit)
             .await?;
 
-        Ok(())
+        // A short page means the start of the history has been reached.
+        let next_cursor = match messages.first() {
+            Some(oldest) if messages.len() == limit as usize => Some(HistoryCursor::of(oldest)),
+            _ => None,
+        };
+
+        Ok((messages, next_cursor))
     }
+}
 
-    pub async fn get_chats_from_today(&self, chatroom_id: i32) -> BoxResult<Vec<String>> {
-        let now = Local::now();
-        let date = now.date().and_hms(0, 0, 0);
-
-        let rows = self
-            .session
-            .query(
-                r#"SELECT content FROM chat WHERE chatroom_id = ? AND ts > ?"#,
-                (&chatroom_id, date.timestamp_millis()),
-            )
-            .await?
-            .rows
-            .expect("Expected row response.")
-            .into_typed::<(String,)>();
-
-        let mut chats = Vec::new();
-
-        for row in rows {
-            match row {
-                Ok((content,)) => {
-                    chats.push(content);
-                }
-                Err(error) => {
-                    error!("Invalid row in data found - {:?}", error);
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use crate::model::Model;
+    use crate::store::MemoryStore;
+    use chrono::Utc;
+    use shared::{ChatMessage, HistoryCursor};
+
+    fn chat(message_id: i64, content: &str) -> ChatMessage {
+        ChatMessage {
+            message_id,
+            user_id: 7,
+            timestamp: Utc::now().timestamp_millis() + message_id,
+            content: content.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn history_is_paginated() {
+        let model = Model::with_store(Box::new(MemoryStore::new()));
+
+        let chats: Vec<ChatMessage> = (0..5).map(|i| chat(i, "chat")).collect();
+        for chat in chats.iter().rev() {
+            model.insert_chat(1, chat).await.unwrap();
         }
+        model.insert_chat(2, &chat(10, "elsewhere")).await.unwrap();
+
+        let (page, cursor) = model.get_history(1, None, 3).await.unwrap();
+        assert_eq!(page, chats[2..]);
+        assert_eq!(cursor, Some(HistoryCursor::of(&chats[2])));
+
+        let (page, cursor) = model.get_history(1, cursor, 3).await.unwrap();
+        assert_eq!(page, chats[..2]);
+        assert_eq!(cursor, None);
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_are_remembered() {
+        let model = Model::with_store(Box::new(MemoryStore::new()));
+        let ttl = std::time::Duration::from_secs(60);
+
+        model
+            .insert_idempotency_key(1, "key", 42, ttl)
+            .await
+            .unwrap();
 
-        Ok(chats)
+        assert_eq!(model.get_idempotency_key(1, "key").await.unwrap(), Some(42));
+        assert_eq!(model.get_idempotency_key(2, "key").await.unwrap(), None);
     }
 }
@@ -0,0 +1,131 @@
+use crate::config::Config;
+use crate::BoxResult;
+use async_trait::async_trait;
+use log::info;
+use shared::config::Storage;
+use shared::{ChatMessage, HistoryCursor};
+
+mod memory;
+mod scylla;
+
+pub use self::memory::MemoryStore;
+pub use self::scylla::ScyllaStore;
+
+/// Persistence used by the chatroom service. Every chat is stored in the partition of its
+/// chatroom and ordered by the time it was sent, then by its id.
+#[async_trait]
+pub trait Store: Send + Sync {
+    async fn insert_chat(&self, chatroom_id: i32, message: &ChatMessage) -> BoxResult<()>;
+
+    /// Returns the `limit` most recent chats in the chatroom sent before `before`, oldest
+    /// first.
+    async fn get_chats_before(
+        &self,
+        chatroom_id: i32,
+        before: Option<HistoryCursor>,
+        limit: u32,
+    ) -> BoxResult<Vec<ChatMessage>>;
+
+    /// Remembers the id of the message sent with an idempotency key for `ttl_secs` seconds.
+    async fn insert_idempotency_key(
+        &self,
+        chatroom_id: i32,
+        idempotency_key: &str,
+        message_id: i64,
+        ttl_secs: u64,
+    ) -> BoxResult<()>;
+
+    /// Returns the id of the message sent with the idempotency key if it is still remembered.
+    async fn get_idempotency_key(
+        &self,
+        chatroom_id: i32,
+        idempotency_key: &str,
+    ) -> BoxResult<Option<i64>>;
+}
+
+/// Opens the store selected by the configuration.
+pub async fn open_store(config: &Config) -> BoxResult<Box<dyn Store>> {
+    match config.storage {
+        Storage::Scylla => {
+            let scylla_urls = config
+                .scylla_url
+                .as_deref()
+                .ok_or("SCYLLA_URL not defined.")?;
+            Ok(Box::new(ScyllaStore::new(scylla_urls, config).await?))
+        }
+        Storage::Memory => {
+            info!("Using in-memory storage. Chats will not survive a restart.");
+            Ok(Box::new(MemoryStore::new()))
+        }
+    }
+}
+
+/// Applies the schema migrations of the store selected by the configuration.
+pub async fn migrate(config: &Config) -> BoxResult<()> {
+    match config.storage {
+        Storage::Scylla => {
+            let scylla_urls = config
+                .scylla_url
+                .as_deref()
+                .ok_or("SCYLLA_URL not defined.")?;
+            self::scylla::run_migrations(scylla_urls, config).await
+        }
+        Storage::Memory => {
+            info!("In-memory storage has no schema to migrate.");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::store::{MemoryStore, ScyllaStore, Store};
+    use shared::{ChatMessage, HistoryCursor};
+
+    /// Checks that a store pages through history oldest first without overlapping pages.
+    async fn check_history_order(store: &dyn Store) {
+        let chatroom_id = rand::random::<i32>();
+        let start = chrono::Utc::now().timestamp_millis();
+
+        // Pairs of chats share a millisecond, which must not make either of them go missing.
+        let chats: Vec<ChatMessage> = (0..5)
+            .map(|i| ChatMessage {
+                message_id: i,
+                user_id: 7,
+                timestamp: start + i / 2,
+                content: format!("chat {}", i),
+            })
+            .collect();
+        for chat in chats.iter().rev() {
+            store.insert_chat(chatroom_id, chat).await.unwrap();
+        }
+
+        let page = store.get_chats_before(chatroom_id, None, 3).await.unwrap();
+        assert_eq!(page, chats[2..]);
+
+        // The oldest chat of a page is the cursor of the next one.
+        let page = store
+            .get_chats_before(chatroom_id, Some(HistoryCursor::of(&page[0])), 3)
+            .await
+            .unwrap();
+        assert_eq!(page, chats[..2]);
+    }
+
+    #[tokio::test]
+    async fn memory_history_is_ordered() {
+        check_history_order(&MemoryStore::new()).await;
+    }
+
+    /// Needs a ScyllaDB node, e.g. `SCYLLA_URL=127.0.0.1:9042 cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn scylla_history_is_ordered() {
+        let scylla_url = std::env::var("SCYLLA_URL").expect("SCYLLA_URL not defined.");
+        let store = ScyllaStore::new(&scylla_url, &Config::default())
+            .await
+            .unwrap();
+
+        check_history_order(&store).await;
+    }
+}
@@ -0,0 +1,98 @@
+use crate::store::Store;
+use crate::BoxResult;
+use async_trait::async_trait;
+use chrono::Utc;
+use shared::{ChatMessage, HistoryCursor};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use tokio::sync::RwLock;
+
+/// A store that keeps every chat in process memory. Intended for local development and tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    chats: RwLock<HashMap<i32, BTreeMap<HistoryCursor, ChatMessage>>>,
+    // Maps a chatroom and idempotency key to a message id and when the key expires.
+    idempotency_keys: RwLock<HashMap<(i32, String), (i64, i64)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn insert_chat(&self, chatroom_id: i32, message: &ChatMessage) -> BoxResult<()> {
+        let mut chats = self.chats.write().await;
+
+        // Mirror the (chatroom_id, ts, message_id) primary key of the chat_message table.
+        chats
+            .entry(chatroom_id)
+            .or_default()
+            .insert(HistoryCursor::of(message), message.clone());
+
+        Ok(())
+    }
+
+    async fn get_chats_before(
+        &self,
+        chatroom_id: i32,
+        before: Option<HistoryCursor>,
+        limit: u32,
+    ) -> BoxResult<Vec<ChatMessage>> {
+        let chats = self.chats.read().await;
+
+        let mut chats: Vec<ChatMessage> = match chats.get(&chatroom_id) {
+            Some(chats) => chats
+                .range((
+                    Bound::Unbounded,
+                    before.map_or(Bound::Unbounded, Bound::Excluded),
+                ))
+                .rev()
+                .take(limit as usize)
+                .map(|(_cursor, message)| message.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        chats.reverse();
+        Ok(chats)
+    }
+
+    async fn insert_idempotency_key(
+        &self,
+        chatroom_id: i32,
+        idempotency_key: &str,
+        message_id: i64,
+        ttl_secs: u64,
+    ) -> BoxResult<()> {
+        let now = Utc::now().timestamp_millis();
+        let expires = now + (ttl_secs as i64) * 1000;
+
+        let mut idempotency_keys = self.idempotency_keys.write().await;
+        idempotency_keys.retain(|_key, (_message_id, expires)| *expires > now);
+        idempotency_keys.insert(
+            (chatroom_id, idempotency_key.to_string()),
+            (message_id, expires),
+        );
+
+        Ok(())
+    }
+
+    async fn get_idempotency_key(
+        &self,
+        chatroom_id: i32,
+        idempotency_key: &str,
+    ) -> BoxResult<Option<i64>> {
+        let now = Utc::now().timestamp_millis();
+
+        let idempotency_keys = self.idempotency_keys.read().await;
+        let message_id = idempotency_keys
+            .get(&(chatroom_id, idempotency_key.to_string()))
+            .filter(|(_message_id, expires)| *expires > now)
+            .map(|(message_id, _expires)| *message_id);
+
+        Ok(message_id)
+    }
+}
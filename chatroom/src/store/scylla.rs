@@ -0,0 +1,285 @@
+use crate::config::Config;
+use crate::store::Store;
+use crate::BoxResult;
+use async_trait::async_trait;
+use chrono::Duration;
+use futures::StreamExt;
+use log::{error, info};
+use scylla::query::Query;
+use scylla::{IntoTypedRows, Session, SessionBuilder};
+use shared::migration::{self, Migration, Step, TaskFuture};
+use shared::{ChatMessage, HistoryCursor};
+
+const KEYSPACE: &str = "searchbuddy";
+
+// How many rows are read per page when copying the chat table.
+const COPY_PAGE_SIZE: i32 = 1000;
+
+// The schema of the chatroom service. Migrations are applied in order and each is recorded in
+// the schema_version table once applied, see shared::migration.
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create the chat_message and chat_idempotency tables",
+        steps: &[
+            Step::Cql(
+                r#"
+                CREATE TABLE IF NOT EXISTS chat_message (
+                    chatroom_id int,
+                    ts timestamp,
+                    message_id bigint,
+                    content text,
+                    user_id int,
+                    PRIMARY KEY(chatroom_id, ts, message_id),
+                );
+                "#,
+            ),
+            Step::Cql(
+                r#"
+                CREATE TABLE IF NOT EXISTS chat_idempotency (
+                    chatroom_id int,
+                    idempotency_key text,
+                    message_id bigint,
+                    PRIMARY KEY((chatroom_id, idempotency_key)),
+                );
+                "#,
+            ),
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Copy chats out of the chat table used before chats carried an id",
+        steps: &[Step::Task(copy_chats)],
+    },
+];
+
+pub struct ScyllaStore {
+    session: Session,
+}
+
+impl ScyllaStore {
+    pub async fn new(scylla_urls: &str, config: &Config) -> BoxResult<Self> {
+        let session = connect(scylla_urls).await?;
+
+        if config.migrate_on_startup {
+            migrate(&session, config, false).await?;
+        } else {
+            session.use_keyspace(KEYSPACE, false).await?;
+        }
+
+        Ok(ScyllaStore { session })
+    }
+}
+
+async fn connect(scylla_urls: &str) -> BoxResult<Session> {
+    let scylla_urls: Vec<&str> = scylla_urls.split_whitespace().collect();
+
+    let session = SessionBuilder::new()
+        .known_nodes(&scylla_urls)
+        .build()
+        .await?;
+
+    Ok(session)
+}
+
+/// Applies the migrations, and the configured replication when `alter_replication` is set.
+async fn migrate(session: &Session, config: &Config, alter_replication: bool) -> BoxResult<()> {
+    migration::run(
+        session,
+        KEYSPACE,
+        "chatroom",
+        &config.replication,
+        alter_replication,
+        MIGRATIONS,
+        &(),
+    )
+    .await
+}
+
+/// Applies the migrations of the chatroom service without starting it.
+pub async fn run_migrations(scylla_urls: &str, config: &Config) -> BoxResult<()> {
+    let session = connect(scylla_urls).await?;
+    migrate(&session, config, true).await
+}
+
+/// Copies every chat of the chat table used by older versions into the chat_message table.
+/// Those chats were keyed by the millisecond they were sent in and have no sender, so they are
+/// copied with an id of 0. Copying a chat twice writes the same row, so this is safe to run
+/// repeatedly.
+fn copy_chats<'a>(session: &'a Session, _context: &'a ()) -> TaskFuture<'a> {
+    Box::pin(async move {
+        let tables = session
+            .query(
+                r#"
+                SELECT table_name
+                FROM system_schema.tables
+                WHERE keyspace_name = ? AND table_name = 'chat'"#,
+                (KEYSPACE,),
+            )
+            .await?
+            .rows
+            .unwrap_or_default();
+
+        // The table doesn't exist for deployments that started on this version.
+        if tables.is_empty() {
+            info!("No legacy chat table to copy.");
+            return Ok(());
+        }
+
+        // The table is read a page at a time so it never has to fit in memory.
+        let mut rows = session
+            .query_iter(
+                Query::new("SELECT chatroom_id, ts, content FROM chat")
+                    .with_page_size(COPY_PAGE_SIZE),
+                (),
+            )
+            .await?
+            .into_typed::<(i32, Duration, String)>();
+
+        let mut copied = 0;
+
+        while let Some(row) = rows.next().await {
+            let (chatroom_id, ts, content) = row?;
+
+            session
+                .query(
+                    r#"
+                    INSERT INTO chat_message (chatroom_id, ts, message_id, content)
+                    VALUES (?, ?, 0, ?);
+                    "#,
+                    (chatroom_id, ts.num_milliseconds(), &content),
+                )
+                .await?;
+
+            copied += 1;
+        }
+
+        info!("Copied {} chats to the chat_message table.", copied);
+
+        Ok(())
+    })
+}
+
+#[async_trait]
+impl Store for ScyllaStore {
+    async fn insert_chat(&self, chatroom_id: i32, message: &ChatMessage) -> BoxResult<()> {
+        self.session
+            .query(
+                r#"
+                INSERT INTO chat_message (chatroom_id, ts, message_id, content, user_id)
+                VALUES (?, ?, ?, ?, ?);
+                "#,
+                (
+                    &chatroom_id,
+                    message.timestamp,
+                    message.message_id,
+                    &message.content,
+                    message.user_id,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_chats_before(
+        &self,
+        chatroom_id: i32,
+        before: Option<HistoryCursor>,
+        limit: u32,
+    ) -> BoxResult<Vec<ChatMessage>> {
+        let (before_ts, before_message_id) = match before {
+            Some(before) => (before.timestamp, before.message_id),
+            None => (i64::MAX, i64::MAX),
+        };
+
+        // Walk the clustering key backwards from the cursor so only one page is read.
+        let rows = self
+            .session
+            .query(
+                r#"
+                SELECT ts, content, message_id, user_id
+                FROM chat_message
+                WHERE chatroom_id = ? AND (ts, message_id) < (?, ?)
+                ORDER BY ts DESC, message_id DESC
+                LIMIT ?"#,
+                (&chatroom_id, before_ts, before_message_id, limit as i32),
+            )
+            .await?
+            .rows
+            .expect("Expected row response.")
+            .into_typed::<(Duration, String, i64, Option<i32>)>();
+
+        let mut chats = Vec::new();
+
+        for row in rows {
+            match row {
+                // Chats stored before senders were recorded have no user.
+                Ok((ts, content, message_id, user_id)) => {
+                    chats.push(ChatMessage {
+                        message_id,
+                        user_id: user_id.unwrap_or_default(),
+                        timestamp: ts.num_milliseconds(),
+                        content,
+                    });
+                }
+                Err(error) => {
+                    error!("Invalid row in data found - {:?}", error);
+                }
+            }
+        }
+
+        // Read newest first, returned oldest first.
+        chats.reverse();
+        Ok(chats)
+    }
+
+    async fn insert_idempotency_key(
+        &self,
+        chatroom_id: i32,
+        idempotency_key: &str,
+        message_id: i64,
+        ttl_secs: u64,
+    ) -> BoxResult<()> {
+        self.session
+            .query(
+                r#"
+                INSERT INTO chat_idempotency (chatroom_id, idempotency_key, message_id)
+                VALUES (?, ?, ?)
+                USING TTL ?;
+                "#,
+                (&chatroom_id, idempotency_key, message_id, ttl_secs as i32),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_idempotency_key(
+        &self,
+        chatroom_id: i32,
+        idempotency_key: &str,
+    ) -> BoxResult<Option<i64>> {
+        let mut rows = self
+            .session
+            .query(
+                r#"
+                SELECT message_id
+                FROM chat_idempotency
+                WHERE chatroom_id = ? AND idempotency_key = ?"#,
+                (&chatroom_id, idempotency_key),
+            )
+            .await?
+            .rows
+            .expect("Expected row response.")
+            .into_typed::<(i64,)>();
+
+        match rows.next() {
+            Some(row) => {
+                let (message_id,) = row?;
+                Ok(Some(message_id))
+            }
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The url of the frontend server used to search for chatrooms.
+    pub server_url: String,
+    /// The region to look for chatrooms in. The server decides when it is not set.
+    pub region: Option<String>,
+    /// The most chatrooms to list for a search. The server decides when it is not set.
+    pub limit: Option<usize>,
+    /// Leaves out chatrooms with fewer users than this.
+    pub min_users: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_url: "http://searchbuddy.gerber.website:8080".to_string(),
+            region: None,
+            limit: None,
+            min_users: None,
+        }
+    }
+}
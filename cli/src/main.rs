@@ -1,11 +1,16 @@
 #![feature(try_blocks)]
 
+use crate::config::Config;
+use chrono::{Local, TimeZone};
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use crossterm::terminal::ClearType;
 use crossterm::{execute, queue};
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use log::error;
-use shared::{initialize_logger, Chatroom, ClientToServerMessage, ServerToClientMessage};
+use shared::{
+    initialize_logger, ChatMessage, Chatroom, ChatroomsResponse, ClientToServerMessage,
+    HistoryCursor, LookupStatus, ServerToClientMessage,
+};
 use std::error::Error;
 use std::io::{stdout, Write};
 use std::pin::Pin;
@@ -15,6 +20,8 @@ use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio_tungstenite::tungstenite::error::Error as WsError;
 use tokio_tungstenite::tungstenite::Message as WsMessage;
 
+mod config;
+
 type BoxError = Box<dyn Error + Send + Sync>;
 type BoxResult<T> = Result<T, BoxError>;
 
@@ -23,13 +30,37 @@ enum Event {
     Keyboard(KeyEvent),
     Disconnect,
     Joined,
-    NewUser { user_id: i32 },
-    UserDisconnected { user_id: i32 },
-    NewMessage(String),
-    ChatsFromTodayResponse { messages: Vec<String> },
+    NewUser {
+        user_id: i32,
+    },
+    UserDisconnected {
+        user_id: i32,
+    },
+    NewMessage(ChatMessage),
+    MessageAck {
+        idempotency_key: String,
+    },
+    HistoryResponse {
+        messages: Vec<ChatMessage>,
+        next_cursor: Option<HistoryCursor>,
+    },
+    Redirect {
+        url: String,
+    },
+    /// The chatroom is no longer hosted by the instance and has to be looked up again.
+    Relocate,
 }
 
+/// The number of chats fetched each time the user scrolls past the oldest loaded chat.
+const HISTORY_PAGE_SIZE: u32 = 50;
+
+/// How many redirects are followed while joining a chatroom before giving up.
+const MAX_REDIRECTS: u32 = 3;
+
+type ChatSink = Pin<Box<dyn Sink<WsMessage, Error = WsError> + Send + Sync>>;
+
 struct Model {
+    config: Config,
     event_sender: UnboundedSender<Event>,
     state: State,
 }
@@ -41,11 +72,25 @@ enum State {
     SelectChatroom {
         chatrooms: Vec<Chatroom>,
         index: isize,
+        /// The terms whose chatrooms couldn't be looked up.
+        unavailable: Vec<String>,
     },
     InChatroom {
-        sink: Pin<Box<dyn Sink<WsMessage, Error = WsError> + Send + Sync>>,
+        chatroom: Chatroom,
+        sink: ChatSink,
         messages: Vec<String>,
         input: String,
+        /// How many lines the view is scrolled up from the newest message.
+        scroll: usize,
+        /// The cursor of the next page of older chats, if there is one.
+        history_cursor: Option<HistoryCursor>,
+        history_pending: bool,
+        /// The number of redirects followed since the chatroom was last joined.
+        redirects: u32,
+        /// Chats sent but not yet acknowledged, as their idempotency key and content. They are
+        /// sent again with the same key after moving to another instance, which drops those
+        /// that already arrived.
+        unacked: Vec<(String, String)>,
     },
     Error {
         error: BoxError,
@@ -53,6 +98,15 @@ enum State {
     Break,
 }
 
+fn format_chat(chat: &ChatMessage) -> String {
+    let time = match Local.timestamp_millis_opt(chat.timestamp).single() {
+        Some(time) => time.format("%H:%M").to_string(),
+        None => "--:--".to_string(),
+    };
+
+    format!("[{}] User {}: {}", time, chat.user_id, chat.content)
+}
+
 fn view(model: &Model) -> BoxResult<()> {
     let (_width, height) = crossterm::terminal::size()?;
 
@@ -67,7 +121,11 @@ fn view(model: &Model) -> BoxResult<()> {
                 crossterm::style::Print(format!("Enter query: {}", search))
             )?;
         }
-        State::SelectChatroom { chatrooms, index } => {
+        State::SelectChatroom {
+            chatrooms,
+            index,
+            unavailable,
+        } => {
             let index = index.rem_euclid(chatrooms.len() as isize) as usize;
 
             for i in 0..chatrooms.len() {
@@ -83,24 +141,43 @@ fn view(model: &Model) -> BoxResult<()> {
                 queue!(
                     stdout,
                     crossterm::style::Print(format!(
-                        "Chatroom {} - {} users",
-                        chatroom.term, chatroom.num_users
+                        "Chatroom {} ({:?}) - {} users, {} recent messages",
+                        chatroom.term, chatroom.kind, chatroom.num_users, chatroom.recent_messages
+                    ))
+                )?;
+            }
+
+            if !unavailable.is_empty() {
+                queue!(
+                    stdout,
+                    crossterm::cursor::MoveTo(0, chatrooms.len() as u16 + 1)
+                )?;
+                queue!(
+                    stdout,
+                    crossterm::style::Print(format!(
+                        "Could not look up: {}",
+                        unavailable.join(", ")
                     ))
                 )?;
             }
         }
         State::InChatroom {
-            messages, input, ..
+            messages,
+            input,
+            scroll,
+            ..
         } => {
             queue!(stdout, crossterm::cursor::MoveTo(0, height))?;
             queue!(stdout, crossterm::style::Print(format!("> {}", input)))?;
 
+            let visible = messages.len().saturating_sub(*scroll);
+
             for i in 0..=(height - 1) {
-                if i as usize > messages.len() {
+                if i as usize > visible {
                     break;
                 }
 
-                let message = messages.get(messages.len() - i as usize);
+                let message = messages.get(visible - i as usize);
                 match message {
                     Some(message) => {
                         queue!(stdout, crossterm::cursor::MoveTo(0, (height - 1) - i))?;
@@ -127,6 +204,94 @@ fn view(model: &Model) -> BoxResult<()> {
     Ok(())
 }
 
+async fn send_chat(
+    sink: &mut ChatSink,
+    idempotency_key: &str,
+    content: &str,
+) -> Result<(), WsError> {
+    let message = serde_json::to_string(&ClientToServerMessage::NewMessage {
+        content: content.to_string(),
+        idempotency_key: Some(idempotency_key.to_string()),
+    })
+    .unwrap();
+    sink.send(WsMessage::Text(message)).await
+}
+
+async fn request_history(
+    sink: &mut ChatSink,
+    before: Option<HistoryCursor>,
+) -> Result<(), WsError> {
+    let message = serde_json::to_string(&ClientToServerMessage::HistoryRequest {
+        before,
+        limit: HISTORY_PAGE_SIZE,
+    })
+    .unwrap();
+    sink.send(WsMessage::Text(message)).await
+}
+
+/// Looks up the chatrooms of a search.
+async fn search_chatrooms(config: &Config, search: &str) -> BoxResult<ChatroomsResponse> {
+    let client = reqwest::Client::new();
+    let mut parameters: Vec<(&str, String)> = vec![("search", search.to_string())];
+    if let Some(region) = &config.region {
+        parameters.push(("region", region.clone()));
+    }
+    if let Some(limit) = config.limit {
+        parameters.push(("limit", limit.to_string()));
+    }
+    if let Some(min_users) = config.min_users {
+        parameters.push(("min_users", min_users.to_string()));
+    }
+
+    let response = client
+        .get(format!("{}/chatrooms", config.server_url))
+        .query(&parameters)
+        .send()
+        .await?
+        .json::<ChatroomsResponse>()
+        .await?;
+
+    Ok(response)
+}
+
+/// Returns the url the chatroom is hosted at now, by searching for its term again.
+async fn locate_chatroom(config: &Config, chatroom: &Chatroom) -> BoxResult<String> {
+    let response = search_chatrooms(config, &chatroom.term).await?;
+
+    response
+        .chatrooms
+        .into_iter()
+        .find(|found| found.chatroom_id == chatroom.chatroom_id)
+        .map(|found| found.url)
+        .ok_or_else(|| "The chatroom could not be found again.".into())
+}
+
+/// Connects to the chatroom instance at `url`, joins the chatroom and forwards what the instance
+/// sends to the event channel.
+async fn join_chatroom(
+    config: &Config,
+    channel: UnboundedSender<Event>,
+    url: &str,
+    chatroom: &Chatroom,
+) -> BoxResult<ChatSink> {
+    let (socket, _response) = tokio_tungstenite::connect_async(url).await?;
+    let (mut send, receive) = socket.split();
+
+    let message = serde_json::to_string(&ClientToServerMessage::Join {
+        chatroom_id: chatroom.chatroom_id,
+        term: Some(chatroom.term.clone()),
+        region: config.region.clone(),
+    })?;
+    send.send(WsMessage::Text(message)).await?;
+
+    let mut sink: ChatSink = Box::pin(send);
+    request_history(&mut sink, None).await?;
+
+    tokio::spawn(handle_messages(channel, Box::pin(receive)));
+
+    Ok(sink)
+}
+
 async fn update(model: &mut Model, event: Event) {
     match &mut model.state {
         State::Initial { search } => match event {
@@ -137,22 +302,21 @@ async fn update(model: &mut Model, event: Event) {
                 }
                 KeyCode::Enter => {
                     if !search.is_empty() {
-                        let client = reqwest::Client::new();
-                        let chatrooms: BoxResult<Vec<Chatroom>> = try {
-                            client
-                                .get("http://searchbuddy.gerber.website:8080/chatrooms")
-                                .query(&[("search", &search)])
-                                .send()
-                                .await?
-                                .json::<Vec<Chatroom>>()
-                                .await?
-                        };
-
-                        match chatrooms {
-                            Ok(chatrooms) => {
+                        let response = search_chatrooms(&model.config, search).await;
+
+                        match response {
+                            Ok(response) => {
+                                let unavailable = response
+                                    .terms
+                                    .into_iter()
+                                    .filter(|term| term.status != LookupStatus::Ok)
+                                    .map(|term| term.term)
+                                    .collect();
+
                                 model.state = State::SelectChatroom {
-                                    chatrooms,
+                                    chatrooms: response.chatrooms,
                                     index: 0,
+                                    unavailable,
                                 };
                             }
                             Err(error) => {
@@ -176,7 +340,9 @@ async fn update(model: &mut Model, event: Event) {
             },
             _ => {}
         },
-        State::SelectChatroom { chatrooms, index } => match event {
+        State::SelectChatroom {
+            chatrooms, index, ..
+        } => match event {
             Event::Keyboard(key_event) => match key_event.code {
                 KeyCode::Esc => {
                     model.state = State::Initial {
@@ -191,39 +357,32 @@ async fn update(model: &mut Model, event: Event) {
                 }
                 KeyCode::Enter => {
                     let index = index.rem_euclid(chatrooms.len() as isize) as usize;
-                    let chatroom = &chatrooms[index];
-
-                    let result: BoxResult<()> = try {
-                        let (socket, _response) =
-                            tokio_tungstenite::connect_async(&chatroom.url).await?;
-
-                        let (mut send, receive) = socket.split();
-
-                        let message = serde_json::to_string(&ClientToServerMessage::Join {
-                            chatroom_id: chatroom.chatroom_id,
-                        })?;
-                        send.send(WsMessage::Text(message)).await?;
-
-                        let message =
-                            serde_json::to_string(&ClientToServerMessage::ChatsFromTodayRequest)?;
-                        send.send(WsMessage::Text(message)).await?;
-
-                        let channel = model.event_sender.clone();
-
-                        tokio::spawn(handle_messages(channel, Box::pin(receive)));
-
-                        model.state = State::InChatroom {
-                            sink: Box::pin(send),
-                            messages: Vec::new(),
-                            input: "".to_string(),
-                        };
-                    };
-
-                    if let Err(error) = result {
-                        error!(
-                            "An error occurred while connecting to the provided chatroom instance."
-                        );
-                        model.state = State::Error { error };
+                    let chatroom = chatrooms[index].clone();
+
+                    let channel = model.event_sender.clone();
+                    let result =
+                        join_chatroom(&model.config, channel, &chatroom.url, &chatroom).await;
+
+                    match result {
+                        Ok(sink) => {
+                            model.state = State::InChatroom {
+                                chatroom,
+                                sink,
+                                messages: Vec::new(),
+                                input: "".to_string(),
+                                scroll: 0,
+                                history_cursor: None,
+                                history_pending: true,
+                                redirects: 0,
+                                unacked: Vec::new(),
+                            };
+                        }
+                        Err(error) => {
+                            error!(
+                                "An error occurred while connecting to the provided chatroom instance."
+                            );
+                            model.state = State::Error { error };
+                        }
                     }
                 }
                 _ => {}
@@ -231,35 +390,62 @@ async fn update(model: &mut Model, event: Event) {
             _ => {}
         },
         State::InChatroom {
+            chatroom,
             sink,
             messages,
             input,
+            scroll,
+            history_cursor,
+            history_pending,
+            redirects,
+            unacked,
         } => match event {
             Event::Keyboard(key_event) => match key_event.code {
                 KeyCode::Esc => model.state = State::Break,
                 KeyCode::Backspace => {
                     input.pop();
                 }
-                KeyCode::Enter => {
-                    if !input.is_empty() {
-                        let message = serde_json::to_string(&ClientToServerMessage::NewMessage {
-                            content: input.clone(),
-                        })
-                        .unwrap();
-                        let result = sink.send(WsMessage::Text(message)).await;
+                KeyCode::Up | KeyCode::PageUp => {
+                    let (_width, height) = crossterm::terminal::size().unwrap_or((0, 0));
+                    let lines = match key_event.code {
+                        KeyCode::PageUp => height as usize,
+                        _ => 1,
+                    };
+                    *scroll = (*scroll + lines).min(messages.len());
+
+                    // Fetch older chats once the oldest loaded chat is on screen.
+                    let at_top = messages.len() - *scroll < height as usize;
+                    if at_top && history_cursor.is_some() && !*history_pending {
+                        let result = request_history(sink, *history_cursor).await;
                         match result {
-                            Ok(()) => {
-                                input.clear();
-                            }
+                            Ok(()) => *history_pending = true,
                             Err(error) => {
-                                error!("An error occurred while sending a message - {:?}", error);
-                                model.state = State::Error {
-                                    error: Box::new(error),
-                                };
+                                error!("An error occurred while requesting history - {:?}", error);
                             }
                         }
                     }
                 }
+                KeyCode::Down => {
+                    *scroll = scroll.saturating_sub(1);
+                }
+                KeyCode::PageDown => {
+                    let (_width, height) = crossterm::terminal::size().unwrap_or((0, 0));
+                    *scroll = scroll.saturating_sub(height as usize);
+                }
+                KeyCode::Enter => {
+                    if !input.is_empty() {
+                        let idempotency_key = format!("{:032x}", rand::random::<u128>());
+                        let result = send_chat(sink, &idempotency_key, input).await;
+                        // The connection may already be closed because the chatroom is moving.
+                        // The chat is then sent again once the chatroom has been joined on the
+                        // new instance.
+                        if let Err(error) = result {
+                            error!("An error occurred while sending a message - {:?}", error);
+                        }
+                        unacked.push((idempotency_key, input.clone()));
+                        input.clear();
+                    }
+                }
                 KeyCode::Char(char) => {
                     if char == 'u' && key_event.modifiers.contains(KeyModifiers::CONTROL) {
                         input.clear();
@@ -276,6 +462,72 @@ async fn update(model: &mut Model, event: Event) {
             }
             Event::Joined => {
                 messages.push("Joined chatroom!".to_string());
+                *redirects = 0;
+            }
+            Event::Redirect { url } => {
+                if *redirects >= MAX_REDIRECTS {
+                    error!(
+                        "Gave up joining the chatroom after {} redirects.",
+                        redirects
+                    );
+                    model.state = State::Initial {
+                        search: "".to_string(),
+                    };
+                    return;
+                }
+
+                let channel = model.event_sender.clone();
+                let result = join_chatroom(&model.config, channel, &url, chatroom).await;
+
+                match result {
+                    Ok(new_sink) => {
+                        *sink = new_sink;
+                        *redirects += 1;
+                        // The latest chats are requested again from the new instance, which
+                        // covers any others sent while moving over.
+                        messages.clear();
+                        *scroll = 0;
+                        *history_cursor = None;
+                        *history_pending = true;
+
+                        for (idempotency_key, content) in unacked.iter() {
+                            let result = send_chat(sink, idempotency_key, content).await;
+                            if let Err(error) = result {
+                                error!("An error occurred while resending a message - {:?}", error);
+                                model.state = State::Error {
+                                    error: Box::new(error),
+                                };
+                                return;
+                            }
+                        }
+                    }
+                    Err(error) => {
+                        error!("An error occurred while following a redirect - {:?}", error);
+                        model.state = State::Error { error };
+                    }
+                }
+            }
+            Event::Relocate => {
+                let result = locate_chatroom(&model.config, chatroom).await;
+
+                match result {
+                    // Joined like a redirect, so that unacknowledged chats are sent again.
+                    Ok(url) => {
+                        let result = model.event_sender.send(Event::Redirect { url });
+                        if let Err(error) = result {
+                            model.state = State::Error {
+                                error: Box::new(error),
+                            };
+                        }
+                    }
+                    Err(error) => {
+                        error!(
+                            "An error occurred while looking the chatroom up again - {:?}",
+                            error
+                        );
+                        model.state = State::Error { error };
+                    }
+                }
             }
             Event::NewUser { user_id } => {
                 messages.push(format!("User with id {} joined chatroom!", user_id));
@@ -284,12 +536,20 @@ async fn update(model: &mut Model, event: Event) {
                 messages.push(format!("User with id {} left chatroom!", user_id));
             }
             Event::NewMessage(chat) => {
-                messages.push(chat);
+                messages.push(format_chat(&chat));
             }
-            Event::ChatsFromTodayResponse {
-                messages: mut new_messages,
+            Event::MessageAck { idempotency_key } => {
+                unacked.retain(|(key, _content)| *key != idempotency_key);
+            }
+            Event::HistoryResponse {
+                messages: older_messages,
+                next_cursor,
             } => {
-                messages.append(&mut new_messages);
+                // Older chats go above everything loaded so far. The scroll offset is measured
+                // from the newest message, so the view does not move.
+                messages.splice(0..0, older_messages.iter().map(format_chat));
+                *history_cursor = next_cursor;
+                *history_pending = false;
             }
         },
         State::Error { .. } => match event {
@@ -324,11 +584,37 @@ async fn handle_messages(
                             ServerToClientMessage::UserDisconnected { user_id } => {
                                 channel.send(Event::UserDisconnected { user_id })?;
                             }
-                            ServerToClientMessage::NewMessage { content } => {
-                                channel.send(Event::NewMessage(content))?;
+                            ServerToClientMessage::NewMessage { message } => {
+                                channel.send(Event::NewMessage(message))?;
+                            }
+                            ServerToClientMessage::MessageAck {
+                                idempotency_key, ..
+                            } => {
+                                // Messages are shown once they are broadcast back to us.
+                                channel.send(Event::MessageAck { idempotency_key })?;
                             }
-                            ServerToClientMessage::ChatsFromTodayResponse { messages } => {
-                                channel.send(Event::ChatsFromTodayResponse { messages })?;
+                            ServerToClientMessage::Reconnect { url: None }
+                            | ServerToClientMessage::Moved => {
+                                // The chatroom continues on whichever instance discovery places
+                                // it on now.
+                                channel.send(Event::Relocate)?;
+                                break;
+                            }
+                            ServerToClientMessage::Reconnect { url: Some(url) }
+                            | ServerToClientMessage::Redirect { url } => {
+                                // The chatroom continues at the url. Nothing else is read from
+                                // this connection, so closing it doesn't end the chat.
+                                channel.send(Event::Redirect { url })?;
+                                break;
+                            }
+                            ServerToClientMessage::HistoryResponse {
+                                messages,
+                                next_cursor,
+                            } => {
+                                channel.send(Event::HistoryResponse {
+                                    messages,
+                                    next_cursor,
+                                })?;
                             }
                         }
                     }
@@ -355,6 +641,8 @@ async fn handle_messages(
 fn main() -> BoxResult<()> {
     initialize_logger()?;
 
+    let config: Config = shared::config::load()?;
+
     let runtime = Runtime::new()?;
 
     let mut stdout = stdout();
@@ -367,6 +655,7 @@ fn main() -> BoxResult<()> {
 
     let handle = runtime.spawn(async move {
         let mut model = Model {
+            config,
             event_sender: send_clone,
             state: State::Initial {
                 search: "".to_string(),
@@ -0,0 +1,58 @@
+use crate::placement::PlacementPolicy;
+use serde::Deserialize;
+use shared::config::Storage;
+use shared::migration::Replication;
+use std::net::SocketAddr;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The address the server listens on.
+    pub bind_address: SocketAddr,
+    /// The region of instances and clients that don't name one.
+    pub region: String,
+    /// Regions to fall back to, in order, when the region preferred by a client has no active
+    /// instances.
+    pub regions: Vec<String>,
+    /// How long an instance stays active after its last ping.
+    pub heartbeat_timeout_secs: i64,
+    /// How often the active instances are reloaded from the store.
+    pub membership_refresh_ms: u64,
+    /// How long a request to /membership waits for a change before returning.
+    pub membership_poll_timeout_secs: u64,
+    /// How new terms are assigned to instances.
+    pub placement_policy: PlacementPolicy,
+    /// Assigns terms with a consistent hashing ring over the active instances instead of
+    /// storing a mapping for every term. The placement policy is not used in this mode.
+    pub consistent_hashing: bool,
+    /// The number of points each instance has on the hashing ring.
+    pub virtual_nodes: u32,
+    pub storage: Storage,
+    /// Whitespace separated list of ScyllaDB nodes.
+    pub scylla_url: Option<String>,
+    /// The replication of the keyspace. Changing it alters the keyspace on the next migration.
+    pub replication: Replication,
+    /// Whether to apply schema migrations on startup. When disabled they are applied by
+    /// running the service with the migrate subcommand.
+    pub migrate_on_startup: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: "0.0.0.0:8081".parse().unwrap(),
+            region: "US1".to_string(),
+            regions: vec!["US1".to_string()],
+            heartbeat_timeout_secs: 10,
+            membership_refresh_ms: 1000,
+            membership_poll_timeout_secs: 30,
+            placement_policy: PlacementPolicy::PowerOfTwoChoices,
+            consistent_hashing: false,
+            virtual_nodes: 64,
+            storage: Storage::Scylla,
+            scylla_url: None,
+            replication: Replication::default(),
+            migrate_on_startup: true,
+        }
+    }
+}
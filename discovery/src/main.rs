@@ -1,32 +1,44 @@
+use crate::config::Config;
 use crate::model::Model;
-use axum::extract::Extension;
+use axum::extract::{Extension, Query};
 use axum::http::StatusCode;
-use axum::routing::post;
+use axum::routing::{get, post};
 use axum::{AddExtensionLayer, Json, Router};
 use log::error;
 use shared::discovery::*;
 use shared::initialize_logger;
 
+use serde::Deserialize;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
+mod config;
+mod membership;
 mod model;
+mod placement;
+mod store;
 
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 type BoxResult<T> = Result<T, BoxError>;
 
 struct State {
     model: Arc<Model>,
+    // How long a request to /membership waits for a change.
+    poll_timeout: Duration,
 }
 
 async fn register(
     Extension(state): Extension<Arc<State>>,
     Json(payload): Json<RegisterRequest>,
 ) -> Result<Json<RegisterResponse>, StatusCode> {
-    let instance_id = state.model.register_instance(&payload.address).await;
+    let result = state
+        .model
+        .register_instance(&payload.address, payload.region.as_deref())
+        .await;
 
-    match instance_id {
-        Ok(instance_id) => Ok(Json::from(RegisterResponse { instance_id })),
+    match result {
+        Ok(response) => Ok(Json::from(response)),
         Err(error) => {
             error!(
                 "An error occurred while registering a new instance - {:?}",
@@ -43,11 +55,23 @@ async fn ping(
 ) -> Result<Json<PingResponse>, StatusCode> {
     let result = state
         .model
-        .ping_instance(&payload.address, payload.instance_id)
+        .ping_instance(
+            &payload.address,
+            payload.region.as_deref(),
+            payload.instance_id,
+            payload.epoch,
+            &payload.load,
+        )
         .await;
 
     match result {
-        Ok(ping_result) => Ok(Json::from(PingResponse { ping_result })),
+        Ok(ping_result) => {
+            let lease = match ping_result {
+                PingResult::Ok => Some(state.model.lease(payload.epoch)),
+                PingResult::NoLongerActive => None,
+            };
+            Ok(Json::from(PingResponse { ping_result, lease }))
+        }
         Err(error) => {
             error!(
                 "An error occurred while receiving a ping from an instance - {:?}",
@@ -58,11 +82,40 @@ async fn ping(
     }
 }
 
+async fn deregister(
+    Extension(state): Extension<Arc<State>>,
+    Json(payload): Json<DeregisterRequest>,
+) -> StatusCode {
+    let result = state
+        .model
+        .deregister_instance(
+            &payload.address,
+            payload.region.as_deref(),
+            payload.instance_id,
+        )
+        .await;
+
+    match result {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(error) => {
+            error!(
+                "An error occurred while deregistering an instance - {:?}",
+                error
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
 async fn chatroom(
     Extension(state): Extension<Arc<State>>,
     Json(payload): Json<ChatroomRequest>,
 ) -> Result<Json<ChatroomResponse>, StatusCode> {
-    let result = state.model.get_chatroom(&payload.term).await;
+    let result = state
+        .model
+        .get_chatroom(&payload.term, payload.region.as_deref())
+        .await;
 
     match result {
         Ok(instance) => Ok(Json::from(ChatroomResponse { instance })),
@@ -76,17 +129,95 @@ async fn chatroom(
     }
 }
 
-async fn async_main() -> BoxResult<()> {
-    let model = Arc::new(Model::new().await?);
-    let state = Arc::new(State { model });
+async fn chatrooms_batch(
+    Extension(state): Extension<Arc<State>>,
+    Json(payload): Json<BatchChatroomRequest>,
+) -> Result<Json<BatchChatroomResponse>, StatusCode> {
+    let result = state
+        .model
+        .get_chatrooms(&payload.terms, payload.region.as_deref())
+        .await;
+
+    match result {
+        Ok(instances) => Ok(Json::from(BatchChatroomResponse { instances })),
+        Err(error) => {
+            error!(
+                "An error occurred while fetching the addresses of chatrooms - {:?}",
+                error
+            );
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MembershipQuery {
+    /// The version the caller last saw. The request waits for a newer one.
+    #[serde(default)]
+    version: u64,
+}
+
+async fn membership(
+    Extension(state): Extension<Arc<State>>,
+    Query(query): Query<MembershipQuery>,
+) -> Json<MembershipResponse> {
+    let membership = state
+        .model
+        .watch_membership(query.version, state.poll_timeout)
+        .await;
+
+    Json::from(membership)
+}
+
+async fn placements(
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<PlacementsResponse>, StatusCode> {
+    let result = state.model.get_placements().await;
+
+    match result {
+        Ok(placements) => Ok(Json::from(placements)),
+        Err(error) => {
+            error!(
+                "An error occurred while fetching the placement of chatrooms - {:?}",
+                error
+            );
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn async_main(config: Config) -> BoxResult<()> {
+    let model = Arc::new(Model::new(&config).await?);
+    let state = Arc::new(State {
+        model: model.clone(),
+        poll_timeout: Duration::from_secs(config.membership_poll_timeout_secs),
+    });
+
+    let refresh_interval = Duration::from_millis(config.membership_refresh_ms);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(refresh_interval).await;
+
+            if let Err(error) = model.refresh_membership().await {
+                error!(
+                    "An error occurred while refreshing the active instances - {:?}",
+                    error
+                );
+            }
+        }
+    });
 
     let app = Router::new()
         .route("/register", post(register))
         .route("/ping", post(ping))
+        .route("/deregister", post(deregister))
         .route("/chatroom", post(chatroom))
+        .route("/chatrooms/batch", post(chatrooms_batch))
+        .route("/membership", get(membership))
+        .route("/placements", get(placements))
         .layer(AddExtensionLayer::new(state));
 
-    axum::Server::bind(&"0.0.0.0:8081".parse().unwrap())
+    axum::Server::bind(&config.bind_address)
         .serve(app.into_make_service())
         .await
         .unwrap();
@@ -98,9 +229,15 @@ fn main() -> BoxResult<()> {
     initialize_logger()?;
     dotenv::dotenv().ok();
 
+    let config: Config = shared::config::load()?;
+
     let runtime = Runtime::new()?;
 
-    runtime.block_on(async_main())?;
+    match shared::config::subcommand().as_deref() {
+        None => runtime.block_on(async_main(config))?,
+        Some("migrate") => runtime.block_on(store::migrate(&config))?,
+        Some(command) => return Err(format!("Unknown command {}.", command).into()),
+    }
 
     Ok(())
 }
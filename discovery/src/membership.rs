@@ -0,0 +1,225 @@
+// An in-memory view of the active instances of every region. Lookups read it instead of the
+// instance table. It is kept up to date by registrations, pings and deregistrations handled by
+// this process, and reloaded from the store on an interval to pick up expired instances and
+// those handled by other replicas.
+//
+// Every instance is kept along with when its lease runs out, and is left out of lookups from
+// then on. Otherwise an instance that stopped pinging would keep being handed out until the
+// next reload, after discovery may already have moved its terms elsewhere.
+//
+// Every change to the set of active instances bumps a version number, which lets clients of
+// the /membership endpoint wait for the next change rather than polling.
+
+use chrono::Utc;
+use shared::discovery::{InstanceStatus, MembershipResponse};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+pub struct Membership {
+    regions: RwLock<HashMap<String, Vec<Member>>>,
+    version: AtomicU64,
+    changed: Notify,
+}
+
+struct Member {
+    status: InstanceStatus,
+    // When the lease of the instance runs out, in milliseconds since the unix epoch.
+    expires: i64,
+}
+
+impl Membership {
+    pub fn new() -> Self {
+        Membership {
+            regions: RwLock::new(HashMap::new()),
+            version: AtomicU64::new(1),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Returns the instances of the region whose lease hasn't run out.
+    pub fn get(&self, region: &str) -> Vec<InstanceStatus> {
+        let now = Utc::now().timestamp_millis();
+        let regions = self.regions.read().unwrap();
+
+        match regions.get(region) {
+            Some(members) => Self::active(members, now),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns every region that has had an active instance.
+    pub fn regions(&self) -> Vec<String> {
+        self.regions.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Replaces the instances of the region with those read from the store, each with when its
+    /// lease runs out.
+    pub fn replace(&self, region: &str, instances: Vec<(InstanceStatus, i64)>) {
+        let mut regions = self.regions.write().unwrap();
+        let current = regions.entry(region.to_string()).or_default();
+
+        let changed = current.len() != instances.len()
+            || instances.iter().any(|(status, _expires)| {
+                !current.iter().any(|current| {
+                    current.status.instance.instance_id == status.instance.instance_id
+                })
+            });
+
+        *current = instances
+            .into_iter()
+            .map(|(status, expires)| Member { status, expires })
+            .collect();
+        drop(regions);
+
+        if changed {
+            self.bump();
+        }
+    }
+
+    /// Adds the instance to the region, or updates its load and lease if it is already there.
+    pub fn upsert(&self, region: &str, status: InstanceStatus, expires: i64) {
+        let mut regions = self.regions.write().unwrap();
+        let members = regions.entry(region.to_string()).or_default();
+
+        let existing = members
+            .iter_mut()
+            .find(|existing| existing.status.instance.address == status.instance.address);
+
+        let changed = match existing {
+            Some(existing) => {
+                let changed = existing.status.instance.instance_id != status.instance.instance_id;
+                *existing = Member { status, expires };
+                changed
+            }
+            None => {
+                members.push(Member { status, expires });
+                true
+            }
+        };
+        drop(regions);
+
+        if changed {
+            self.bump();
+        }
+    }
+
+    pub fn remove(&self, region: &str, instance_id: i32) {
+        let mut regions = self.regions.write().unwrap();
+
+        let removed = match regions.get_mut(region) {
+            Some(members) => {
+                let count = members.len();
+                members.retain(|member| member.status.instance.instance_id != instance_id);
+                members.len() != count
+            }
+            None => false,
+        };
+        drop(regions);
+
+        if removed {
+            self.bump();
+        }
+    }
+
+    pub fn snapshot(&self) -> MembershipResponse {
+        // The version is read first, so a change made while copying is reported again.
+        let version = self.version();
+        let now = Utc::now().timestamp_millis();
+        let instances = self
+            .regions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(region, members)| (region.clone(), Self::active(members, now)))
+            .collect();
+
+        MembershipResponse { version, instances }
+    }
+
+    /// Waits until the version is newer than `since` or the timeout passes, then returns the
+    /// current membership.
+    pub async fn wait_for_change(&self, since: u64, timeout: Duration) -> MembershipResponse {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            // Registered before checking the version, so a change in between isn't missed.
+            let changed = self.changed.notified();
+
+            if self.version() > since {
+                break;
+            }
+
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                break;
+            }
+        }
+
+        self.snapshot()
+    }
+
+    // Leases running out don't bump the version, but the next reload from the store does once
+    // the instance has expired there too.
+    fn active(members: &[Member], now: i64) -> Vec<InstanceStatus> {
+        members
+            .iter()
+            .filter(|member| member.expires > now)
+            .map(|member| member.status)
+            .collect()
+    }
+
+    fn bump(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::membership::Membership;
+    use chrono::Utc;
+    use shared::discovery::{Instance, InstanceStatus, Load};
+    use std::net::SocketAddrV4;
+
+    fn status(instance_id: i32, port: u16) -> InstanceStatus {
+        InstanceStatus {
+            instance: Instance {
+                instance_id,
+                address: SocketAddrV4::new([127, 0, 0, 1].into(), port),
+            },
+            load: Load::default(),
+        }
+    }
+
+    fn ids(statuses: &[InstanceStatus]) -> Vec<i32> {
+        statuses
+            .iter()
+            .map(|status| status.instance.instance_id)
+            .collect()
+    }
+
+    #[test]
+    fn instances_with_lapsed_leases_are_left_out() {
+        let membership = Membership::new();
+        let now = Utc::now().timestamp_millis();
+
+        membership.replace("US1", vec![(status(1, 3000), now + 10_000)]);
+        membership.upsert("US1", status(2, 3001), now - 1);
+        assert_eq!(ids(&membership.get("US1")), vec![1]);
+        assert_eq!(ids(&membership.snapshot().instances["US1"]), vec![1]);
+
+        // A ping renews the lease.
+        membership.upsert("US1", status(2, 3001), now + 10_000);
+        assert_eq!(ids(&membership.get("US1")), vec![1, 2]);
+
+        membership.replace("US1", vec![(status(1, 3000), now - 1)]);
+        assert!(membership.get("US1").is_empty());
+    }
+}
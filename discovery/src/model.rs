@@ -1,249 +1,783 @@
+use crate::config::Config;
+use crate::membership::Membership;
+use crate::placement::{self, PlacementPolicy};
+use crate::store::{open_store, MappingUpdate, Store};
 use crate::BoxResult;
-use async_recursion::async_recursion;
 use chrono::{Duration, Utc};
-use log::error;
-use rand::prelude::IteratorRandom;
+use futures::future::join_all;
 use rand::thread_rng;
-use scylla::{IntoTypedRows, Session, SessionBuilder};
 use shared::discovery::*;
-use std::env;
+use shared::hash_ring::HashRing;
+use std::collections::{HashMap, VecDeque};
 use std::iter::Iterator;
 use std::net::SocketAddrV4;
+use std::sync::Mutex;
+
+/// The number of placements kept for debugging.
+const MAX_PLACEMENTS: usize = 100;
+
+/// How many times assigning a term is retried when it is reassigned to an expired instance
+/// concurrently.
+const MAX_ASSIGNMENT_ATTEMPTS: usize = 5;
 
 pub struct Model {
-    pub session: Session,
+    store: Box<dyn Store>,
+    membership: Membership,
+    heartbeat_timeout: Duration,
+    // The region of instances and clients that don't name one.
+    default_region: String,
+    // Regions to fall back to, in order, when the preferred region has no active instances.
+    regions: Vec<String>,
+    placement_policy: PlacementPolicy,
+    // Set when terms are assigned by a hashing ring rather than the stored mapping.
+    virtual_nodes: Option<u32>,
+    // The number of terms placed on each instance since it last reported its load.
+    pending: Mutex<HashMap<i32, u32>>,
+    placements: Mutex<VecDeque<Placement>>,
 }
 
 impl Model {
-    pub async fn new() -> BoxResult<Self> {
-        let scylla_urls = env::var("SCYLLA_URL")?;
-        let scylla_urls: Vec<&str> = scylla_urls.split_whitespace().collect();
-
-        // Generate all tables in the database.
-        let session = SessionBuilder::new()
-            .known_nodes(&scylla_urls)
-            .build()
-            .await?;
+    pub async fn new(config: &Config) -> BoxResult<Self> {
+        let store = open_store(config).await?;
+        let model = Self::with_store(config, store);
+        model.refresh_membership().await?;
+        Ok(model)
+    }
 
-        session
-            .query(
-                r#"
-                CREATE KEYSPACE IF NOT EXISTS searchbuddy
-                WITH REPLICATION = {
-                    'class': 'SimpleStrategy',
-                    'replication_factor': 1
-                };
-                "#,
-                (),
-            )
-            .await?;
+    pub fn with_store(config: &Config, store: Box<dyn Store>) -> Self {
+        Model {
+            store,
+            membership: Membership::new(),
+            heartbeat_timeout: Duration::seconds(config.heartbeat_timeout_secs),
+            default_region: config.region.clone(),
+            regions: config.regions.clone(),
+            placement_policy: config.placement_policy,
+            virtual_nodes: config.consistent_hashing.then_some(config.virtual_nodes),
+            pending: Mutex::new(HashMap::new()),
+            placements: Mutex::new(VecDeque::new()),
+        }
+    }
 
-        session.use_keyspace("searchbuddy", false).await?;
-
-        session
-            .query(
-                r#"
-                CREATE TABLE IF NOT EXISTS instance (
-                    region text,
-                    address text,
-                    instance_id int,
-                    last_accessed bigint,
-                    PRIMARY KEY(region, address),
-                );
-                "#,
-                (),
-            )
-            .await?;
+    fn region<'a>(&'a self, region: Option<&'a str>) -> &'a str {
+        region.unwrap_or(&self.default_region)
+    }
 
-        session
-            .query(
-                r#"
-                CREATE TABLE IF NOT EXISTS chatroom (
-                    term text,
-                    address text,
-                    instance_id int,
-                    PRIMARY KEY(term),
-                );
-                "#,
-                (),
-            )
+    /// Registers an instance and grants it a lease with a new epoch. Pings must carry that
+    /// epoch, so a registration that was replaced can't be renewed.
+    pub async fn register_instance(
+        &self,
+        address: &SocketAddrV4,
+        region: Option<&str>,
+    ) -> BoxResult<RegisterResponse> {
+        let region = self.region(region);
+        let instance_id = rand::random::<i32>();
+        let lease = self.lease(self.store.next_epoch().await?);
+
+        let instance = Instance {
+            instance_id,
+            address: *address,
+        };
+
+        self.store
+            .insert_instance(region, &instance, lease.epoch)
             .await?;
 
-        Ok(Model { session })
+        let status = InstanceStatus {
+            instance,
+            load: Load::default(),
+        };
+        self.membership
+            .upsert(region, status, self.lease_deadline());
+
+        Ok(RegisterResponse { instance_id, lease })
     }
 
-    pub async fn register_instance(&self, address: &SocketAddrV4) -> BoxResult<i32> {
-        let instance_id = rand::random::<i32>();
-        let now = Utc::now();
-
-        self.session
-            .query(
-                r#"
-                INSERT INTO instance (region, address, instance_id, last_accessed)
-                VALUES (?, ?, ?, ?);
-                "#,
-                (
-                    &"US1",
-                    &format!("{}", address),
-                    &instance_id,
-                    now.timestamp_millis(),
-                ),
-            )
-            .await?;
+    /// Returns the lease with the epoch as renewed by a successful ping. Leases last as long as
+    /// discovery waits for the next ping.
+    pub fn lease(&self, epoch: i64) -> Lease {
+        Lease {
+            epoch,
+            duration_ms: self.heartbeat_timeout.num_milliseconds() as u64,
+        }
+    }
 
-        Ok(instance_id)
+    /// Returns when a lease granted or renewed now runs out, in milliseconds since the unix
+    /// epoch.
+    fn lease_deadline(&self) -> i64 {
+        (Utc::now() + self.heartbeat_timeout).timestamp_millis()
     }
 
+    /// Renews the registration of an instance. Pings with an epoch other than the one the
+    /// instance registered with, including those without one, are refused as if the instance
+    /// was unknown, so that it registers again.
     pub async fn ping_instance(
         &self,
         address: &SocketAddrV4,
+        region: Option<&str>,
         instance_id: i32,
+        epoch: i64,
+        load: &Load,
     ) -> BoxResult<PingResult> {
-        let now = Utc::now();
-        let threshold = now.checked_sub_signed(Duration::seconds(10)).unwrap();
-
-        let mut rows = self
-            .session
-            .query(
-                r#"
-                SELECT address, instance_id
-                FROM instance
-                WHERE region = ? and address = ? and instance_id = ? and last_accessed >= ?
-                ALLOW FILTERING"#,
-                (
-                    &"US1",
-                    &format!("{}", address),
-                    &instance_id,
-                    threshold.timestamp_millis(),
-                ),
-            )
-            .await?
-            .rows
-            .expect("Expected row response.")
-            .into_typed::<(String, i32)>();
-
-        if let Some(row) = rows.next() {
-            let (address, _instance_id) = row?;
-
-            let now = Utc::now();
-
-            self.session
-                .query(
-                    r#"
-                    UPDATE instance
-                    SET last_accessed = ?
-                    WHERE region = ? and address = ?"#,
-                    (now.timestamp_millis(), &"US1", &address),
-                )
-                .await?;
+        let region = self.region(region);
+        let instance = Instance {
+            instance_id,
+            address: *address,
+        };
+
+        let active = self
+            .store
+            .refresh_instance(region, &instance, epoch, load)
+            .await?;
 
+        if active {
+            // The reported load now includes the terms placed since the last ping.
+            self.pending.lock().unwrap().remove(&instance_id);
+
+            let status = InstanceStatus {
+                instance,
+                load: *load,
+            };
+            self.membership
+                .upsert(region, status, self.lease_deadline());
             Ok(PingResult::Ok)
         } else {
+            self.membership.remove(region, instance_id);
             Ok(PingResult::NoLongerActive)
         }
     }
 
-    #[async_recursion]
-    pub async fn get_chatroom(&self, term: &str) -> BoxResult<Option<Instance>> {
-        let active_instances = self.get_instances().await?;
+    /// Removes an instance from the registry. Terms mapped to it are reassigned the next time
+    /// they are looked up, since only active instances are considered.
+    pub async fn deregister_instance(
+        &self,
+        address: &SocketAddrV4,
+        region: Option<&str>,
+        instance_id: i32,
+    ) -> BoxResult<bool> {
+        let region = self.region(region);
+        let instance = Instance {
+            instance_id,
+            address: *address,
+        };
 
-        let mut rows = self
-            .session
-            .query(
-                r#"
-                SELECT term, address, instance_id
-                FROM chatroom
-                WHERE term = ?"#,
-                (term,),
-            )
-            .await?
-            .rows
-            .expect("Expected row response.")
-            .into_typed::<(String, String, i32)>();
-
-        let mut instance: Option<Instance> = None;
-        if let Some(row) = rows.next() {
-            let (_term, _address, instance_id) = row?;
-            if let Some(active_instance) = active_instances
+        self.pending.lock().unwrap().remove(&instance_id);
+        self.membership.remove(region, instance_id);
+        self.store.remove_instance(region, &instance).await
+    }
+
+    /// Returns the instance hosting the chatroom of a term for clients in the given region.
+    /// Terms are placed on instances in that region while it has any, and otherwise on the
+    /// first fallback region that does. Each region keeps its own mapping, so clients in
+    /// different regions get different chatrooms.
+    pub async fn get_chatroom(
+        &self,
+        term: &str,
+        region: Option<&str>,
+    ) -> BoxResult<Option<Instance>> {
+        let mut instances = self.get_chatrooms(&[term.to_string()], region).await?;
+        Ok(instances.remove(term))
+    }
+
+    /// Returns the instance hosting the chatroom of every term that could be placed. The
+    /// active instances are read once and the terms are looked up concurrently.
+    pub async fn get_chatrooms(
+        &self,
+        terms: &[String],
+        region: Option<&str>,
+    ) -> BoxResult<HashMap<String, Instance>> {
+        let region = self.region(region);
+        let active_instances = self.get_serving_instances(region).await?;
+
+        if let Some(virtual_nodes) = self.virtual_nodes {
+            let instances: Vec<Instance> = active_instances
+                .iter()
+                .map(|status| status.instance)
+                .collect();
+            let ring = HashRing::new(&instances, virtual_nodes);
+
+            return Ok(terms
                 .iter()
-                .find(|instance| instance.instance_id == instance_id)
-            {
-                instance = Some(*active_instance);
+                .filter_map(|term| Some((term.clone(), *ring.get(term)?)))
+                .collect());
+        }
+
+        let lookups = terms
+            .iter()
+            .map(|term| self.assign_term(term, region, &active_instances));
+        let results = join_all(lookups).await;
+
+        let mut instances = HashMap::new();
+        for (term, result) in terms.iter().zip(results) {
+            if let Some(instance) = result? {
+                instances.insert(term.clone(), instance);
             }
         }
 
-        return match instance {
-            Some(instance) => Ok(Some(instance)),
-            None => {
-                // Either there is no associated instance or the associated instance is no longer
-                // valid. Choose a new instance.
-
-                let new_instance = active_instances.iter().choose(&mut thread_rng());
-
-                match new_instance {
-                    Some(new_instance) => {
-                        self.session
-                            .query(
-                                r#"
-                                INSERT INTO chatroom (term, address, instance_id)
-                                VALUES (?, ?, ?)
-                                "#,
-                                (
-                                    term,
-                                    &format!("{}", new_instance.address),
-                                    &new_instance.instance_id,
-                                ),
-                            )
-                            .await?;
-
-                        self.get_chatroom(term).await
-                    }
-
-                    None => Ok(None),
+        Ok(instances)
+    }
+
+    /// Returns the instance the term is mapped to, mapping it to a new one if it is unmapped or
+    /// its instance is not among the active instances.
+    async fn assign_term(
+        &self,
+        term: &str,
+        region: &str,
+        active_instances: &[InstanceStatus],
+    ) -> BoxResult<Option<Instance>> {
+        let find_active = |instance_id: i32| {
+            active_instances
+                .iter()
+                .find(|status| status.instance.instance_id == instance_id)
+                .map(|status| status.instance)
+        };
+
+        let mut mapping = self.store.get_mapping(region, term).await?;
+
+        for _attempt in 0..MAX_ASSIGNMENT_ATTEMPTS {
+            if let Some(instance) = mapping.and_then(find_active) {
+                return Ok(Some(instance));
+            }
+
+            // Either there is no associated instance or the associated instance is no longer
+            // valid. Choose a new instance, unless another request beats us to it.
+            let mut candidates = active_instances.to_vec();
+            let new_instance = match self.choose_instance(&mut candidates) {
+                Some(new_instance) => new_instance,
+                None => return Ok(None),
+            };
+
+            let update = self
+                .store
+                .compare_and_set_mapping(region, term, mapping, &new_instance)
+                .await?;
+
+            match update {
+                MappingUpdate::Applied => {
+                    self.record_placement(term, region, &new_instance, candidates);
+                    return Ok(Some(new_instance));
                 }
+                // If the term was reassigned to an instance that has expired since, it is
+                // replaced on the next attempt.
+                MappingUpdate::Conflict(current) => mapping = current,
+            }
+        }
+
+        Err(format!("Failed to assign {} to an instance.", term).into())
+    }
+
+    /// Chooses the instance a new term is placed on. The load of every candidate is updated
+    /// with the terms placed on it since its last ping.
+    fn choose_instance(&self, candidates: &mut [InstanceStatus]) -> Option<Instance> {
+        let pending = self.pending.lock().unwrap();
+
+        for candidate in candidates.iter_mut() {
+            if let Some(count) = pending.get(&candidate.instance.instance_id) {
+                candidate.load.chatrooms += count;
             }
+        }
+
+        let chosen = placement::choose(self.placement_policy, candidates, &mut thread_rng())?;
+        Some(chosen.instance)
+    }
+
+    fn record_placement(
+        &self,
+        term: &str,
+        region: &str,
+        instance: &Instance,
+        candidates: Vec<InstanceStatus>,
+    ) {
+        *self
+            .pending
+            .lock()
+            .unwrap()
+            .entry(instance.instance_id)
+            .or_insert(0) += 1;
+
+        let mut placements = self.placements.lock().unwrap();
+        if placements.len() == MAX_PLACEMENTS {
+            placements.pop_front();
+        }
+
+        placements.push_back(Placement {
+            term: term.to_string(),
+            region: region.to_string(),
+            instance_id: instance.instance_id,
+            timestamp: Utc::now().timestamp_millis(),
+            candidates,
+        });
+    }
+
+    /// Returns the active instances of every known region and the most recent placements.
+    pub async fn get_placements(&self) -> BoxResult<PlacementsResponse> {
+        let mut instances = HashMap::new();
+
+        for region in self.region_order(&self.default_region) {
+            instances.insert(region.to_string(), self.get_instances(region).await?);
+        }
+
+        let placements = self.placements.lock().unwrap().iter().cloned().collect();
+
+        Ok(PlacementsResponse {
+            instances,
+            placements,
+        })
+    }
+
+    /// Returns the preferred region followed by the fallback regions.
+    fn region_order<'a>(&'a self, preferred: &'a str) -> Vec<&'a str> {
+        let mut regions = vec![preferred];
+
+        for region in &self.regions {
+            if region != preferred {
+                regions.push(region);
+            }
+        }
+
+        regions
+    }
+
+    /// Returns the active instances of the first region in the fallback order that has any.
+    async fn get_serving_instances(&self, region: &str) -> BoxResult<Vec<InstanceStatus>> {
+        for region in self.region_order(region) {
+            let instances = self.get_instances(region).await?;
+
+            if !instances.is_empty() {
+                return Ok(instances);
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    async fn get_instances(&self, region: &str) -> BoxResult<Vec<InstanceStatus>> {
+        Ok(self.membership.get(region))
+    }
+
+    /// Reloads the active instances of every known region from the store.
+    pub async fn refresh_membership(&self) -> BoxResult<()> {
+        let mut regions = self.membership.regions();
+        for region in self.region_order(&self.default_region) {
+            if !regions.iter().any(|known| known == region) {
+                regions.push(region.to_string());
+            }
+        }
+
+        for region in regions {
+            let instances = self.store.get_instances(&region).await?;
+            self.membership.replace(&region, instances);
+        }
+
+        Ok(())
+    }
+
+    /// Waits for an instance to join or leave after `version`, then returns the active
+    /// instances of every region.
+    pub async fn watch_membership(
+        &self,
+        version: u64,
+        timeout: std::time::Duration,
+    ) -> MembershipResponse {
+        self.membership.wait_for_change(version, timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::model::Model;
+    use crate::placement::PlacementPolicy;
+    use crate::store::{MappingUpdate, MemoryStore, Store};
+    use crate::BoxResult;
+    use async_trait::async_trait;
+    use futures::future::join_all;
+    use shared::discovery::{Instance, InstanceStatus, Load, PingResult};
+    use std::collections::HashMap;
+    use std::net::SocketAddrV4;
+    use std::time::Duration;
+
+    fn model() -> Model {
+        Model::with_store(
+            &Config::default(),
+            Box::new(MemoryStore::new(&Config::default())),
+        )
+    }
+
+    /// Yields after reading a mapping, so that concurrent lookups all see the term unmapped
+    /// before any of them assigns it.
+    struct YieldingStore(MemoryStore);
+
+    #[async_trait]
+    impl Store for YieldingStore {
+        async fn insert_instance(
+            &self,
+            region: &str,
+            instance: &Instance,
+            epoch: i64,
+        ) -> BoxResult<()> {
+            self.0.insert_instance(region, instance, epoch).await
+        }
+
+        async fn refresh_instance(
+            &self,
+            region: &str,
+            instance: &Instance,
+            epoch: i64,
+            load: &Load,
+        ) -> BoxResult<bool> {
+            self.0.refresh_instance(region, instance, epoch, load).await
+        }
+
+        async fn remove_instance(&self, region: &str, instance: &Instance) -> BoxResult<bool> {
+            self.0.remove_instance(region, instance).await
+        }
+
+        async fn get_instances(&self, region: &str) -> BoxResult<Vec<(InstanceStatus, i64)>> {
+            self.0.get_instances(region).await
+        }
+
+        async fn get_mapping(&self, region: &str, term: &str) -> BoxResult<Option<i32>> {
+            let mapping = self.0.get_mapping(region, term).await;
+            tokio::task::yield_now().await;
+            mapping
+        }
+
+        async fn compare_and_set_mapping(
+            &self,
+            region: &str,
+            term: &str,
+            expected: Option<i32>,
+            instance: &Instance,
+        ) -> BoxResult<MappingUpdate> {
+            self.0
+                .compare_and_set_mapping(region, term, expected, instance)
+                .await
+        }
+
+        async fn next_epoch(&self) -> BoxResult<i64> {
+            self.0.next_epoch().await
+        }
+    }
+
+    #[tokio::test]
+    async fn ping_registered_instance() {
+        let model = model();
+        let address: SocketAddrV4 = "127.0.0.1:3000".parse().unwrap();
+
+        let registration = model.register_instance(&address, None).await.unwrap();
+        let instance_id = registration.instance_id;
+        let epoch = registration.lease.epoch;
+
+        let result = model
+            .ping_instance(&address, None, instance_id, epoch, &Load::default())
+            .await
+            .unwrap();
+        assert!(matches!(result, PingResult::Ok));
+
+        let result = model
+            .ping_instance(
+                &address,
+                None,
+                instance_id.wrapping_add(1),
+                epoch,
+                &Load::default(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(result, PingResult::NoLongerActive));
+    }
+
+    #[tokio::test]
+    async fn lease_epochs_increase() {
+        let model = model();
+        let address: SocketAddrV4 = "127.0.0.1:3000".parse().unwrap();
+
+        let first = model.register_instance(&address, None).await.unwrap().lease;
+        let second = model.register_instance(&address, None).await.unwrap().lease;
+        assert!(second.epoch > first.epoch);
+
+        // A lease must not outlive the registration it belongs to.
+        assert_eq!(
+            first.duration_ms,
+            model.heartbeat_timeout.num_milliseconds() as u64
+        );
+        assert_eq!(model.lease(first.epoch), first);
+    }
+
+    #[tokio::test]
+    async fn stale_epoch_is_refused() {
+        let model = model();
+        let address: SocketAddrV4 = "127.0.0.1:3000".parse().unwrap();
+
+        let registration = model.register_instance(&address, None).await.unwrap();
+        let instance_id = registration.instance_id;
+        let epoch = registration.lease.epoch;
+
+        // Pings of an older registration, or without an epoch, don't renew the lease.
+        for stale in [epoch - 1, 0] {
+            let result = model
+                .ping_instance(&address, None, instance_id, stale, &Load::default())
+                .await
+                .unwrap();
+            assert!(matches!(result, PingResult::NoLongerActive));
+        }
+
+        let result = model
+            .ping_instance(&address, None, instance_id, epoch, &Load::default())
+            .await
+            .unwrap();
+        assert!(matches!(result, PingResult::Ok));
+    }
+
+    #[tokio::test]
+    async fn chatroom_mapping_is_stable() {
+        let model = model();
+        assert!(model.get_chatroom("rust", None).await.unwrap().is_none());
+
+        for port in 3000..3004 {
+            let address = SocketAddrV4::new("127.0.0.1".parse().unwrap(), port);
+            model.register_instance(&address, None).await.unwrap();
+        }
+
+        let first = model.get_chatroom("rust", None).await.unwrap().unwrap();
+        for _ in 0..10 {
+            let instance = model.get_chatroom("rust", None).await.unwrap().unwrap();
+            assert_eq!(instance.instance_id, first.instance_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn deregistered_instance_is_replaced() {
+        let model = model();
+
+        let mut epochs = HashMap::new();
+        for port in 3000..3002 {
+            let address = SocketAddrV4::new("127.0.0.1".parse().unwrap(), port);
+            let registration = model.register_instance(&address, None).await.unwrap();
+            epochs.insert(registration.instance_id, registration.lease.epoch);
+        }
+
+        let first = model.get_chatroom("rust", None).await.unwrap().unwrap();
+        let epoch = epochs[&first.instance_id];
+        let removed = model
+            .deregister_instance(&first.address, None, first.instance_id)
+            .await
+            .unwrap();
+        assert!(removed);
+
+        let result = model
+            .ping_instance(
+                &first.address,
+                None,
+                first.instance_id,
+                epoch,
+                &Load::default(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(result, PingResult::NoLongerActive));
+
+        let second = model.get_chatroom("rust", None).await.unwrap().unwrap();
+        assert_ne!(second.instance_id, first.instance_id);
+    }
+
+    #[tokio::test]
+    async fn concurrent_lookups_converge_on_one_instance() {
+        let config = Config {
+            placement_policy: PlacementPolicy::Random,
+            ..Config::default()
+        };
+        let model = Model::with_store(&config, Box::new(YieldingStore(MemoryStore::new(&config))));
+
+        for port in 3000..3008 {
+            let address = SocketAddrV4::new("127.0.0.1".parse().unwrap(), port);
+            model.register_instance(&address, None).await.unwrap();
+        }
+
+        let lookups = (0..32).map(|_| model.get_chatroom("rust", None));
+        let instances: Vec<Instance> = join_all(lookups)
+            .await
+            .into_iter()
+            .map(|instance| instance.unwrap().unwrap())
+            .collect();
+
+        for instance in &instances {
+            assert_eq!(instance.instance_id, instances[0].instance_id);
+        }
+
+        let placements = model.get_placements().await.unwrap();
+        assert_eq!(placements.placements.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn batch_lookup_matches_single_lookups() {
+        let model = model();
+
+        for port in 3000..3004 {
+            let address = SocketAddrV4::new("127.0.0.1".parse().unwrap(), port);
+            model.register_instance(&address, None).await.unwrap();
+        }
+
+        let terms: Vec<String> = ["rust", "go", "zig", "rust"]
+            .iter()
+            .map(|term| term.to_string())
+            .collect();
+        let instances = model.get_chatrooms(&terms, None).await.unwrap();
+        assert_eq!(instances.len(), 3);
+
+        for (term, instance) in instances {
+            let single = model.get_chatroom(&term, None).await.unwrap().unwrap();
+            assert_eq!(single.instance_id, instance.instance_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn terms_stay_in_the_preferred_region() {
+        let config = Config {
+            regions: vec!["US1".to_string(), "EU1".to_string()],
+            ..Config::default()
         };
+        let model = Model::with_store(&config, Box::new(MemoryStore::new(&config)));
+
+        let us: SocketAddrV4 = "127.0.0.1:3000".parse().unwrap();
+        let us_id = model
+            .register_instance(&us, Some("US1"))
+            .await
+            .unwrap()
+            .instance_id;
+
+        // Without instances of its own, Europe falls back to the US.
+        let instance = model.get_chatroom("rust", Some("EU1")).await.unwrap();
+        assert_eq!(instance.unwrap().instance_id, us_id);
+
+        let eu: SocketAddrV4 = "127.0.0.1:3001".parse().unwrap();
+        let eu_id = model
+            .register_instance(&eu, Some("EU1"))
+            .await
+            .unwrap()
+            .instance_id;
+
+        let instance = model.get_chatroom("rust", Some("EU1")).await.unwrap();
+        assert_eq!(instance.unwrap().instance_id, eu_id);
+
+        let instance = model.get_chatroom("rust", None).await.unwrap();
+        assert_eq!(instance.unwrap().instance_id, us_id);
     }
 
-    async fn get_instances(&self) -> BoxResult<Vec<Instance>> {
-        let now = Utc::now();
-        let threshold = now.checked_sub_signed(Duration::seconds(10)).unwrap();
+    #[tokio::test]
+    async fn membership_changes_wake_watchers() {
+        let model = model();
+        let version = model.watch_membership(0, Duration::ZERO).await.version;
+
+        let address: SocketAddrV4 = "127.0.0.1:3000".parse().unwrap();
+        let (membership, registration) = tokio::join!(
+            model.watch_membership(version, Duration::from_secs(5)),
+            model.register_instance(&address, None)
+        );
 
-        let rows = self
-            .session
-            .query(
-                r#"
-                SELECT address, instance_id
-                FROM instance
-                WHERE region = ? and last_accessed >= ?
-                ALLOW FILTERING"#,
-                (&"US1", threshold.timestamp_millis()),
+        let registration = registration.unwrap();
+        let instance_id = registration.instance_id;
+        assert!(membership.version > version);
+        assert_eq!(membership.instances["US1"].len(), 1);
+
+        // Pings only update the load, which is not a change in membership.
+        let version = membership.version;
+        model
+            .ping_instance(
+                &address,
+                None,
+                instance_id,
+                registration.lease.epoch,
+                &Load::default(),
             )
-            .await?
-            .rows
-            .expect("Expected row response.")
-            .into_typed::<(String, i32)>();
-
-        let mut instances = Vec::new();
-
-        for row in rows {
-            match row {
-                Ok((address, instance_id)) => {
-                    let address: SocketAddrV4 = address
-                        .parse()
-                        .expect("Invalid address stored in database.");
-
-                    instances.push(Instance {
-                        instance_id,
-                        address,
-                    });
-                }
-                Err(error) => {
-                    error!("Invalid row in data found - {:?}", error);
-                }
+            .await
+            .unwrap();
+        let membership = model.watch_membership(version, Duration::ZERO).await;
+        assert_eq!(membership.version, version);
+
+        model
+            .deregister_instance(&address, None, instance_id)
+            .await
+            .unwrap();
+        let membership = model.watch_membership(version, Duration::ZERO).await;
+        assert!(membership.version > version);
+        assert!(membership.instances["US1"].is_empty());
+    }
+
+    #[tokio::test]
+    async fn consistent_hashing_only_moves_terms_of_removed_instance() {
+        let config = Config {
+            consistent_hashing: true,
+            ..Config::default()
+        };
+        let model = Model::with_store(&config, Box::new(MemoryStore::new(&config)));
+
+        for port in 3000..3004 {
+            let address = SocketAddrV4::new("127.0.0.1".parse().unwrap(), port);
+            model.register_instance(&address, None).await.unwrap();
+        }
+
+        let terms: Vec<String> = (0..100).map(|index| format!("term{}", index)).collect();
+        let mut owners = Vec::new();
+        for term in &terms {
+            owners.push(model.get_chatroom(term, None).await.unwrap().unwrap());
+        }
+
+        let removed = owners[0];
+        model
+            .deregister_instance(&removed.address, None, removed.instance_id)
+            .await
+            .unwrap();
+
+        for (term, owner) in terms.iter().zip(owners) {
+            let instance = model.get_chatroom(term, None).await.unwrap().unwrap();
+
+            if owner.instance_id == removed.instance_id {
+                assert_ne!(instance.instance_id, removed.instance_id);
+            } else {
+                assert_eq!(instance.instance_id, owner.instance_id);
             }
         }
+    }
 
-        Ok(instances)
+    #[tokio::test]
+    async fn new_terms_avoid_loaded_instances() {
+        let config = Config {
+            placement_policy: PlacementPolicy::LeastLoad,
+            ..Config::default()
+        };
+        let model = Model::with_store(&config, Box::new(MemoryStore::new(&config)));
+
+        let busy: SocketAddrV4 = "127.0.0.1:3000".parse().unwrap();
+        let registration = model.register_instance(&busy, None).await.unwrap();
+        let busy_id = registration.instance_id;
+        let load = Load {
+            chatrooms: 20,
+            users: 80,
+            cpu: 60.0,
+        };
+        model
+            .ping_instance(&busy, None, busy_id, registration.lease.epoch, &load)
+            .await
+            .unwrap();
+
+        for port in 3001..3003 {
+            let address = SocketAddrV4::new("127.0.0.1".parse().unwrap(), port);
+            model.register_instance(&address, None).await.unwrap();
+        }
+
+        let mut counts = [0; 2];
+        for term in ["rust", "go", "zig", "java", "kotlin", "swift"] {
+            let instance = model.get_chatroom(term, None).await.unwrap().unwrap();
+            assert_ne!(instance.instance_id, busy_id);
+            counts[(instance.address.port() - 3001) as usize] += 1;
+        }
+
+        // Terms placed since the last ping count towards the load of an instance.
+        assert_eq!(counts, [3, 3]);
+
+        let placements = model.get_placements().await.unwrap();
+        assert_eq!(placements.instances["US1"].len(), 3);
+        assert_eq!(placements.placements.len(), 6);
     }
 }
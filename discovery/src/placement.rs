@@ -0,0 +1,101 @@
+// New terms are placed according to the load instances report with their pings. Loads are only
+// as fresh as the last ping, so the model adds the chatrooms it has placed on an instance since
+// then before comparing them. Otherwise every new term would go to the same instance until it
+// reported again.
+
+use rand::seq::index::sample;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+use shared::discovery::{InstanceStatus, Load};
+
+/// How a new term is assigned to an instance.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlacementPolicy {
+    /// Use the instance with the least load.
+    LeastLoad,
+    /// Pick two instances at random and use the one with less load.
+    PowerOfTwoChoices,
+    /// Pick an instance at random, ignoring load.
+    Random,
+}
+
+/// Reduces a load to a single number. Every open chatroom, connected user and percent of CPU
+/// usage counts as one.
+pub fn score(load: &Load) -> f64 {
+    load.chatrooms as f64 + load.users as f64 + load.cpu as f64
+}
+
+pub fn choose<'a, R: Rng>(
+    policy: PlacementPolicy,
+    candidates: &'a [InstanceStatus],
+    rng: &mut R,
+) -> Option<&'a InstanceStatus> {
+    match policy {
+        PlacementPolicy::LeastLoad => least_loaded(candidates.iter()),
+        PlacementPolicy::PowerOfTwoChoices => {
+            if candidates.len() < 2 {
+                return candidates.first();
+            }
+
+            let indices = sample(rng, candidates.len(), 2);
+            least_loaded(indices.iter().map(|index| &candidates[index]))
+        }
+        PlacementPolicy::Random => candidates.choose(rng),
+    }
+}
+
+fn least_loaded<'a>(
+    candidates: impl Iterator<Item = &'a InstanceStatus>,
+) -> Option<&'a InstanceStatus> {
+    candidates.min_by(|a, b| score(&a.load).total_cmp(&score(&b.load)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::placement::{choose, PlacementPolicy};
+    use rand::thread_rng;
+    use shared::discovery::{Instance, InstanceStatus, Load};
+    use std::net::SocketAddrV4;
+
+    fn candidates(users: &[u32]) -> Vec<InstanceStatus> {
+        users
+            .iter()
+            .enumerate()
+            .map(|(index, users)| InstanceStatus {
+                instance: Instance {
+                    instance_id: index as i32,
+                    address: SocketAddrV4::new("127.0.0.1".parse().unwrap(), 3000 + index as u16),
+                },
+                load: Load {
+                    users: *users,
+                    ..Load::default()
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn least_load_picks_the_idlest_instance() {
+        let candidates = candidates(&[12, 3, 40]);
+
+        let chosen = choose(PlacementPolicy::LeastLoad, &candidates, &mut thread_rng()).unwrap();
+        assert_eq!(chosen.instance.instance_id, 1);
+    }
+
+    #[test]
+    fn power_of_two_choices_never_picks_the_busiest_instance() {
+        let candidates = candidates(&[12, 3, 40]);
+
+        for _ in 0..100 {
+            let chosen = choose(
+                PlacementPolicy::PowerOfTwoChoices,
+                &candidates,
+                &mut thread_rng(),
+            )
+            .unwrap();
+            assert_ne!(chosen.instance.instance_id, 2);
+        }
+    }
+}
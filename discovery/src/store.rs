@@ -0,0 +1,100 @@
+use crate::config::Config;
+use crate::BoxResult;
+use async_trait::async_trait;
+use log::info;
+use shared::config::Storage;
+use shared::discovery::{Instance, InstanceStatus, Load};
+
+mod memory;
+mod scylla;
+
+pub use self::memory::MemoryStore;
+pub use self::scylla::ScyllaStore;
+
+/// Persistence used by the discovery service. It holds the registry of chatroom instances and
+/// the mapping from terms to the instance hosting their chatroom, both partitioned by region.
+/// Instances expire once they haven't been refreshed for the heartbeat timeout.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Registers the instance under the epoch of the lease it was granted.
+    async fn insert_instance(&self, region: &str, instance: &Instance, epoch: i64)
+        -> BoxResult<()>;
+
+    /// Postpones the expiry of the instance and updates the load it reported, but only if it
+    /// was registered under `epoch`. Returns false if the instance is unknown, has already
+    /// expired or holds another epoch.
+    async fn refresh_instance(
+        &self,
+        region: &str,
+        instance: &Instance,
+        epoch: i64,
+        load: &Load,
+    ) -> BoxResult<bool>;
+
+    /// Removes the instance from the registry. Returns false if the instance is unknown, which
+    /// includes another instance having since registered with the same address.
+    async fn remove_instance(&self, region: &str, instance: &Instance) -> BoxResult<bool>;
+
+    /// Returns every instance that hasn't expired, along with the load it last reported and when
+    /// it expires in milliseconds since the unix epoch.
+    async fn get_instances(&self, region: &str) -> BoxResult<Vec<(InstanceStatus, i64)>>;
+
+    /// Returns the id of the instance the term was last mapped to for clients in the region.
+    async fn get_mapping(&self, region: &str, term: &str) -> BoxResult<Option<i32>>;
+
+    /// Maps the term to the instance for clients in the region, but only if it is still mapped
+    /// to `expected`, or not mapped at all when `expected` is None.
+    async fn compare_and_set_mapping(
+        &self,
+        region: &str,
+        term: &str,
+        expected: Option<i32>,
+        instance: &Instance,
+    ) -> BoxResult<MappingUpdate>;
+
+    /// Returns a lease epoch greater than every epoch returned before, by any replica.
+    async fn next_epoch(&self) -> BoxResult<i64>;
+}
+
+/// The outcome of a conditional update of a mapping.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MappingUpdate {
+    Applied,
+    /// A concurrent caller changed the mapping first. Holds the id of the instance the term is
+    /// mapped to now.
+    Conflict(Option<i32>),
+}
+
+/// Opens the store selected by the configuration.
+pub async fn open_store(config: &Config) -> BoxResult<Box<dyn Store>> {
+    match config.storage {
+        Storage::Scylla => {
+            let scylla_urls = config
+                .scylla_url
+                .as_deref()
+                .ok_or("SCYLLA_URL not defined.")?;
+            Ok(Box::new(ScyllaStore::new(scylla_urls, config).await?))
+        }
+        Storage::Memory => {
+            info!("Using in-memory storage. Registrations will not survive a restart.");
+            Ok(Box::new(MemoryStore::new(config)))
+        }
+    }
+}
+
+/// Applies the schema migrations of the store selected by the configuration.
+pub async fn migrate(config: &Config) -> BoxResult<()> {
+    match config.storage {
+        Storage::Scylla => {
+            let scylla_urls = config
+                .scylla_url
+                .as_deref()
+                .ok_or("SCYLLA_URL not defined.")?;
+            self::scylla::run_migrations(scylla_urls, config).await
+        }
+        Storage::Memory => {
+            info!("In-memory storage has no schema to migrate.");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::Config;
+use crate::store::{MappingUpdate, Store};
+use crate::BoxResult;
+use async_trait::async_trait;
+use chrono::Utc;
+use shared::discovery::{Instance, InstanceStatus, Load};
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::sync::RwLock;
+
+/// A store that keeps the registry in process memory. Intended for local development and tests.
+pub struct MemoryStore {
+    // Keyed by region and address to mirror the primary key of the instance table.
+    instances: RwLock<HashMap<(String, SocketAddrV4), StoredInstance>>,
+    // Keyed by region and term.
+    chatrooms: RwLock<HashMap<(String, String), i32>>,
+    epoch: AtomicI64,
+    // How long an instance stays registered without being refreshed, like the TTL of the
+    // instance_heartbeat table.
+    heartbeat_timeout_ms: i64,
+}
+
+struct StoredInstance {
+    status: InstanceStatus,
+    epoch: i64,
+    // Milliseconds since the unix epoch.
+    expires: i64,
+}
+
+impl MemoryStore {
+    pub fn new(config: &Config) -> Self {
+        MemoryStore {
+            instances: RwLock::new(HashMap::new()),
+            chatrooms: RwLock::new(HashMap::new()),
+            epoch: AtomicI64::new(0),
+            heartbeat_timeout_ms: config.heartbeat_timeout_secs * 1000,
+        }
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn insert_instance(
+        &self,
+        region: &str,
+        instance: &Instance,
+        epoch: i64,
+    ) -> BoxResult<()> {
+        let now = Utc::now().timestamp_millis();
+        let mut instances = self.instances.write().await;
+        let status = InstanceStatus {
+            instance: *instance,
+            load: Load::default(),
+        };
+        instances.insert(
+            (region.to_string(), instance.address),
+            StoredInstance {
+                status,
+                epoch,
+                expires: now + self.heartbeat_timeout_ms,
+            },
+        );
+        Ok(())
+    }
+
+    async fn refresh_instance(
+        &self,
+        region: &str,
+        instance: &Instance,
+        epoch: i64,
+        load: &Load,
+    ) -> BoxResult<bool> {
+        let now = Utc::now().timestamp_millis();
+        let mut instances = self.instances.write().await;
+
+        match instances.get_mut(&(region.to_string(), instance.address)) {
+            Some(stored)
+                if stored.status.instance.instance_id == instance.instance_id
+                    && stored.epoch == epoch
+                    && stored.expires > now =>
+            {
+                stored.status.load = *load;
+                stored.expires = now + self.heartbeat_timeout_ms;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_instance(&self, region: &str, instance: &Instance) -> BoxResult<bool> {
+        let mut instances = self.instances.write().await;
+        let key = (region.to_string(), instance.address);
+
+        match instances.get(&key) {
+            Some(stored) if stored.status.instance.instance_id == instance.instance_id => {
+                instances.remove(&key);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn get_instances(&self, region: &str) -> BoxResult<Vec<(InstanceStatus, i64)>> {
+        let now = Utc::now().timestamp_millis();
+        let instances = self.instances.read().await;
+
+        Ok(instances
+            .iter()
+            .filter(|((stored_region, _address), stored)| {
+                stored_region == region && stored.expires > now
+            })
+            .map(|(_key, stored)| (stored.status, stored.expires))
+            .collect())
+    }
+
+    async fn get_mapping(&self, region: &str, term: &str) -> BoxResult<Option<i32>> {
+        let chatrooms = self.chatrooms.read().await;
+        Ok(chatrooms
+            .get(&(region.to_string(), term.to_string()))
+            .copied())
+    }
+
+    async fn compare_and_set_mapping(
+        &self,
+        region: &str,
+        term: &str,
+        expected: Option<i32>,
+        instance: &Instance,
+    ) -> BoxResult<MappingUpdate> {
+        let mut chatrooms = self.chatrooms.write().await;
+        let key = (region.to_string(), term.to_string());
+
+        let current = chatrooms.get(&key).copied();
+        if current != expected {
+            return Ok(MappingUpdate::Conflict(current));
+        }
+
+        chatrooms.insert(key, instance.instance_id);
+        Ok(MappingUpdate::Applied)
+    }
+
+    async fn next_epoch(&self) -> BoxResult<i64> {
+        Ok(self.epoch.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+}
@@ -0,0 +1,557 @@
+use crate::config::Config;
+use crate::store::{MappingUpdate, Store};
+use crate::BoxResult;
+use async_trait::async_trait;
+use log::{error, info};
+use scylla::{IntoTypedRows, QueryResult, Session, SessionBuilder};
+use shared::discovery::{Instance, InstanceStatus, Load};
+use shared::migration::{self, Migration, Step, TaskFuture};
+use std::net::SocketAddrV4;
+
+// How many times registering retries when the row at the address expires in between trying to
+// insert and to replace it.
+const MAX_INSERT_ATTEMPTS: usize = 3;
+
+// Instances are kept in the instance_heartbeat table. Every registration and ping writes the
+// whole row with a TTL of the heartbeat timeout, so an instance that stops pinging disappears
+// on its own and the active instances of a region are a single partition read.
+//
+// Older versions kept instances in the instance table and filtered on last_accessed, and kept
+// mappings in the chatroom table keyed by the term alone. Migration 2 copies the instances that
+// are still active and every mapping over, see copy_legacy_tables. Once no discovery service
+// of an older version is running, both tables can be dropped.
+
+const KEYSPACE: &str = "searchbuddy";
+
+static MIGRATIONS: &[Migration<Config>] = &[
+    Migration {
+        version: 1,
+        description: "Create the instance_heartbeat and chatroom_by_region tables",
+        steps: &[
+            Step::Cql(
+                r#"
+                CREATE TABLE IF NOT EXISTS instance_heartbeat (
+                    region text,
+                    address text,
+                    instance_id int,
+                    chatrooms int,
+                    users int,
+                    cpu float,
+                    PRIMARY KEY(region, address),
+                );
+                "#,
+            ),
+            Step::Cql(
+                r#"
+                CREATE TABLE IF NOT EXISTS chatroom_by_region (
+                    region text,
+                    term text,
+                    address text,
+                    instance_id int,
+                    PRIMARY KEY((region, term)),
+                );
+                "#,
+            ),
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Copy instances and mappings out of the instance and chatroom tables",
+        steps: &[Step::Task(copy_legacy_tables)],
+    },
+    Migration {
+        version: 3,
+        description: "Create the lease_epoch table",
+        steps: &[Step::Cql(
+            r#"
+            CREATE TABLE IF NOT EXISTS lease_epoch (
+                name text,
+                epoch bigint,
+                PRIMARY KEY(name),
+            );
+            "#,
+        )],
+    },
+    Migration {
+        version: 4,
+        description: "Record the lease epoch of every instance",
+        steps: &[Step::AddColumn {
+            table: "instance_heartbeat",
+            column: "epoch",
+            cql_type: "bigint",
+        }],
+    },
+];
+
+pub struct ScyllaStore {
+    session: Session,
+    // The TTL of heartbeat rows.
+    heartbeat_timeout_secs: i32,
+}
+
+impl ScyllaStore {
+    pub async fn new(scylla_urls: &str, config: &Config) -> BoxResult<Self> {
+        let session = connect(scylla_urls).await?;
+
+        if config.migrate_on_startup {
+            migrate(&session, config, false).await?;
+        } else {
+            session.use_keyspace(KEYSPACE, false).await?;
+        }
+
+        Ok(ScyllaStore {
+            session,
+            heartbeat_timeout_secs: config.heartbeat_timeout_secs as i32,
+        })
+    }
+}
+
+async fn connect(scylla_urls: &str) -> BoxResult<Session> {
+    let scylla_urls: Vec<&str> = scylla_urls.split_whitespace().collect();
+
+    let session = SessionBuilder::new()
+        .known_nodes(&scylla_urls)
+        .build()
+        .await?;
+
+    Ok(session)
+}
+
+/// Applies the migrations, and the configured replication when `alter_replication` is set.
+async fn migrate(session: &Session, config: &Config, alter_replication: bool) -> BoxResult<()> {
+    migration::run(
+        session,
+        KEYSPACE,
+        "discovery",
+        &config.replication,
+        alter_replication,
+        MIGRATIONS,
+        config,
+    )
+    .await
+}
+
+/// Applies the migrations of the discovery service without starting it.
+pub async fn run_migrations(scylla_urls: &str, config: &Config) -> BoxResult<()> {
+    let session = connect(scylla_urls).await?;
+    migrate(&session, config, true).await
+}
+
+/// Copies active instances and mappings out of the tables used by older versions. Mappings are
+/// placed in the default region. Rows already present in the new tables are left alone, so
+/// this is safe to run repeatedly.
+fn copy_legacy_tables<'a>(session: &'a Session, config: &'a Config) -> TaskFuture<'a> {
+    Box::pin(async move {
+        let result = session
+            .query(
+                "SELECT region, address, instance_id, last_accessed FROM instance",
+                (),
+            )
+            .await;
+
+        // The table doesn't exist for deployments that started on this version.
+        if let Ok(result) = result {
+            let now = chrono::Utc::now().timestamp_millis();
+            let rows = result
+                .rows
+                .expect("Expected row response.")
+                .into_typed::<(String, String, i32, i64)>();
+
+            for row in rows {
+                let (region, address, instance_id, last_accessed) = row?;
+
+                // Only the time the instance has left is carried over.
+                let remaining_secs = config.heartbeat_timeout_secs - (now - last_accessed) / 1000;
+                if remaining_secs <= 0 {
+                    continue;
+                }
+
+                session
+                    .query(
+                        r#"
+                        INSERT INTO instance_heartbeat (
+                            region, address, instance_id, chatrooms, users, cpu
+                        )
+                        VALUES (?, ?, ?, 0, 0, 0)
+                        IF NOT EXISTS
+                        USING TTL ?
+                        "#,
+                        (&region, &address, instance_id, remaining_secs as i32),
+                    )
+                    .await?;
+
+                info!("Migrated instance {} in region {}.", address, region);
+            }
+        } else {
+            info!("No legacy instance table to migrate.");
+        }
+
+        let result = session
+            .query("SELECT term, address, instance_id FROM chatroom", ())
+            .await;
+
+        if let Ok(result) = result {
+            let rows = result
+                .rows
+                .expect("Expected row response.")
+                .into_typed::<(String, String, i32)>();
+
+            let mut count = 0;
+            for row in rows {
+                let (term, address, instance_id) = row?;
+
+                session
+                    .query(
+                        r#"
+                        INSERT INTO chatroom_by_region (region, term, address, instance_id)
+                        VALUES (?, ?, ?, ?)
+                        IF NOT EXISTS
+                        "#,
+                        (&config.region, &term, &address, instance_id),
+                    )
+                    .await?;
+                count += 1;
+            }
+
+            info!(
+                "Migrated {} legacy mappings to region {}.",
+                count, config.region
+            );
+        } else {
+            info!("No legacy chatroom table to migrate.");
+        }
+
+        Ok(())
+    })
+}
+
+/// Reads the response of a conditional statement. Returns whether it was applied and, when it
+/// was not, the current instance_id of the row if it exists.
+fn conditional_result(result: QueryResult) -> BoxResult<(bool, Option<i32>)> {
+    let (applied_index, _spec) = result
+        .get_column_spec("[applied]")
+        .ok_or("Expected a conditional response.")?;
+    let instance_index = result
+        .get_column_spec("instance_id")
+        .map(|(index, _spec)| index);
+
+    let row = result
+        .rows
+        .expect("Expected row response.")
+        .into_iter()
+        .next()
+        .ok_or("Expected a conditional response.")?;
+
+    let applied = row.columns[applied_index]
+        .as_ref()
+        .and_then(|value| value.as_boolean())
+        .unwrap_or(false);
+
+    let instance_id = instance_index
+        .and_then(|index| row.columns[index].as_ref())
+        .and_then(|value| value.as_int());
+
+    Ok((applied, instance_id))
+}
+
+#[async_trait]
+impl Store for ScyllaStore {
+    async fn insert_instance(
+        &self,
+        region: &str,
+        instance: &Instance,
+        epoch: i64,
+    ) -> BoxResult<()> {
+        let address = format!("{}", instance.address);
+
+        // Every write to the table is conditional, since Scylla doesn't order plain writes
+        // against the conditional ones of pings and deregistrations. A row left at the address
+        // by an earlier registration is replaced, but never one granted a later epoch. Rows
+        // registered before epochs were recorded have none and expire within the heartbeat
+        // timeout, after which the insert succeeds.
+        for _attempt in 0..MAX_INSERT_ATTEMPTS {
+            let result = self
+                .session
+                .query(
+                    r#"
+                    INSERT INTO instance_heartbeat (
+                        region, address, instance_id, epoch, chatrooms, users, cpu
+                    )
+                    VALUES (?, ?, ?, ?, 0, 0, 0)
+                    IF NOT EXISTS
+                    USING TTL ?
+                    "#,
+                    (
+                        region,
+                        &address,
+                        &instance.instance_id,
+                        epoch,
+                        self.heartbeat_timeout_secs,
+                    ),
+                )
+                .await?;
+
+            let (applied, _instance_id) = conditional_result(result)?;
+            if applied {
+                return Ok(());
+            }
+
+            let result = self
+                .session
+                .query(
+                    r#"
+                    UPDATE instance_heartbeat
+                    USING TTL ?
+                    SET instance_id = ?, epoch = ?, chatrooms = 0, users = 0, cpu = 0
+                    WHERE region = ? and address = ?
+                    IF epoch < ?"#,
+                    (
+                        self.heartbeat_timeout_secs,
+                        &instance.instance_id,
+                        epoch,
+                        region,
+                        &address,
+                        epoch,
+                    ),
+                )
+                .await?;
+
+            let (applied, _instance_id) = conditional_result(result)?;
+            if applied {
+                return Ok(());
+            }
+        }
+
+        Err(format!(
+            "Failed to register instance {} in region {}, a registration with a later epoch holds its address.",
+            address, region
+        )
+        .into())
+    }
+
+    async fn refresh_instance(
+        &self,
+        region: &str,
+        instance: &Instance,
+        epoch: i64,
+        load: &Load,
+    ) -> BoxResult<bool> {
+        // Every column is written so that the whole row gets the new TTL. The condition fails
+        // if the row has expired, belongs to another instance or to an older registration.
+        let result = self
+            .session
+            .query(
+                r#"
+                UPDATE instance_heartbeat
+                USING TTL ?
+                SET instance_id = ?, epoch = ?, chatrooms = ?, users = ?, cpu = ?
+                WHERE region = ? and address = ?
+                IF instance_id = ? AND epoch = ?"#,
+                (
+                    self.heartbeat_timeout_secs,
+                    &instance.instance_id,
+                    epoch,
+                    load.chatrooms as i32,
+                    load.users as i32,
+                    load.cpu,
+                    region,
+                    &format!("{}", instance.address),
+                    &instance.instance_id,
+                    epoch,
+                ),
+            )
+            .await?;
+
+        let (applied, _instance_id) = conditional_result(result)?;
+        Ok(applied)
+    }
+
+    async fn remove_instance(&self, region: &str, instance: &Instance) -> BoxResult<bool> {
+        let result = self
+            .session
+            .query(
+                r#"
+                DELETE FROM instance_heartbeat
+                WHERE region = ? and address = ?
+                IF instance_id = ?"#,
+                (
+                    region,
+                    &format!("{}", instance.address),
+                    &instance.instance_id,
+                ),
+            )
+            .await?;
+
+        let (applied, _instance_id) = conditional_result(result)?;
+        Ok(applied)
+    }
+
+    async fn get_instances(&self, region: &str) -> BoxResult<Vec<(InstanceStatus, i64)>> {
+        // Expired instances have already been removed by their TTL, and the TTL left is when
+        // the others expire. Every column is written with the same TTL.
+        let now = chrono::Utc::now().timestamp_millis();
+        let rows = self
+            .session
+            .query(
+                r#"
+                SELECT address, instance_id, chatrooms, users, cpu, TTL(instance_id)
+                FROM instance_heartbeat
+                WHERE region = ?"#,
+                (region,),
+            )
+            .await?
+            .rows
+            .expect("Expected row response.")
+            .into_typed::<(String, i32, i32, i32, f32, Option<i32>)>();
+
+        let mut instances = Vec::new();
+
+        for row in rows {
+            match row {
+                Ok((address, instance_id, chatrooms, users, cpu, ttl_secs)) => {
+                    let address: SocketAddrV4 = address
+                        .parse()
+                        .expect("Invalid address stored in database.");
+
+                    let status = InstanceStatus {
+                        instance: Instance {
+                            instance_id,
+                            address,
+                        },
+                        load: Load {
+                            chatrooms: chatrooms as u32,
+                            users: users as u32,
+                            cpu,
+                        },
+                    };
+                    let expires = now + ttl_secs.unwrap_or_default() as i64 * 1000;
+
+                    instances.push((status, expires));
+                }
+                Err(error) => {
+                    error!("Invalid row in data found - {:?}", error);
+                }
+            }
+        }
+
+        Ok(instances)
+    }
+
+    async fn get_mapping(&self, region: &str, term: &str) -> BoxResult<Option<i32>> {
+        let mut rows = self
+            .session
+            .query(
+                r#"
+                SELECT term, address, instance_id
+                FROM chatroom_by_region
+                WHERE region = ? and term = ?"#,
+                (region, term),
+            )
+            .await?
+            .rows
+            .expect("Expected row response.")
+            .into_typed::<(String, String, i32)>();
+
+        match rows.next() {
+            Some(row) => {
+                let (_term, _address, instance_id) = row?;
+                Ok(Some(instance_id))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn compare_and_set_mapping(
+        &self,
+        region: &str,
+        term: &str,
+        expected: Option<i32>,
+        instance: &Instance,
+    ) -> BoxResult<MappingUpdate> {
+        let address = format!("{}", instance.address);
+
+        let result = match expected {
+            None => {
+                self.session
+                    .query(
+                        r#"
+                        INSERT INTO chatroom_by_region (region, term, address, instance_id)
+                        VALUES (?, ?, ?, ?)
+                        IF NOT EXISTS
+                        "#,
+                        (region, term, &address, &instance.instance_id),
+                    )
+                    .await?
+            }
+            Some(expected) => {
+                self.session
+                    .query(
+                        r#"
+                        UPDATE chatroom_by_region
+                        SET address = ?, instance_id = ?
+                        WHERE region = ? and term = ?
+                        IF instance_id = ?
+                        "#,
+                        (&address, &instance.instance_id, region, term, expected),
+                    )
+                    .await?
+            }
+        };
+
+        // When the statement was not applied the row holds the current mapping.
+        match conditional_result(result)? {
+            (true, _instance_id) => Ok(MappingUpdate::Applied),
+            (false, instance_id) => Ok(MappingUpdate::Conflict(instance_id)),
+        }
+    }
+
+    async fn next_epoch(&self) -> BoxResult<i64> {
+        // Counters can't be read back atomically with their increment, so the epoch is a plain
+        // column advanced with a conditional update that is retried until it wins.
+        loop {
+            let mut rows = self
+                .session
+                .query("SELECT epoch FROM lease_epoch WHERE name = 'lease'", ())
+                .await?
+                .rows
+                .expect("Expected row response.")
+                .into_typed::<(i64,)>();
+
+            let result = match rows.next() {
+                Some(row) => {
+                    let (epoch,) = row?;
+                    let result = self
+                        .session
+                        .query(
+                            r#"
+                            UPDATE lease_epoch SET epoch = ?
+                            WHERE name = 'lease'
+                            IF epoch = ?"#,
+                            (epoch + 1, epoch),
+                        )
+                        .await?;
+                    (result, epoch + 1)
+                }
+                None => {
+                    let result = self
+                        .session
+                        .query(
+                            r#"
+                            INSERT INTO lease_epoch (name, epoch)
+                            VALUES ('lease', 1)
+                            IF NOT EXISTS"#,
+                            (),
+                        )
+                        .await?;
+                    (result, 1)
+                }
+            };
+
+            let (result, epoch) = result;
+            let (applied, _instance_id) = conditional_result(result)?;
+            if applied {
+                return Ok(epoch);
+            }
+        }
+    }
+}
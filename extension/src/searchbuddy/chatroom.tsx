@@ -3,15 +3,35 @@ import type { Chatroom, Msg } from "./searchbuddy";
 
 type ClientToServerMessage =
     | { type: "Join"; chatroom_id: number }
-    | { type: "NewMessage"; content: string }
-    | { type: "ChatsFromTodayRequest" };
+    | { type: "NewMessage"; content: string; idempotency_key: string }
+    | { type: "HistoryRequest"; before: HistoryCursor | null; limit: number };
+
+interface ChatMessage {
+    message_id: number;
+    user_id: number;
+    timestamp: number;
+    content: string;
+}
+
+interface HistoryCursor {
+    timestamp: number;
+    message_id: number;
+}
 
 type ServerToClientMessage =
     | { type: "Joined"; chatroom_id: number }
     | { type: "NewUser"; user_id: number }
     | { type: "UserDisconnected"; user_id: number }
-    | { type: "NewMessage"; content: string }
-    | { type: "ChatsFromTodayResponse"; messages: string[] };
+    | { type: "NewMessage"; message: ChatMessage }
+    | { type: "MessageAck"; idempotency_key: string; message_id: number }
+    | { type: "Reconnect"; url: string | null }
+    | { type: "Moved" }
+    | { type: "Redirect"; url: string }
+    | {
+          type: "HistoryResponse";
+          messages: ChatMessage[];
+          next_cursor: HistoryCursor | null;
+      };
 
 interface ChatroomModel {
     connected: boolean;
@@ -24,8 +44,20 @@ type ChatroomMsg =
     | { type: "Joined"; chatroom_id: number }
     | { type: "NewUser"; user_id: number }
     | { type: "UserDisconnected"; user_id: number }
-    | { type: "NewMessage"; content: string }
-    | { type: "ChatsFromTodayResponse"; messages: string[] };
+    | { type: "NewMessage"; message: ChatMessage }
+    | {
+          type: "HistoryResponse";
+          messages: ChatMessage[];
+          next_cursor: HistoryCursor | null;
+      };
+
+function formatChat(chat: ChatMessage): string {
+    const time = new Date(chat.timestamp).toLocaleTimeString([], {
+        hour: "2-digit",
+        minute: "2-digit",
+    });
+    return `[${time}] User ${chat.user_id}: ${chat.content}`;
+}
 
 function reducer(model: ChatroomModel, msg: ChatroomMsg): ChatroomModel {
     switch (msg.type) {
@@ -49,10 +81,10 @@ function reducer(model: ChatroomModel, msg: ChatroomMsg): ChatroomModel {
             model.messages.push(`User with id ${msg.user_id} left chatroom!`);
             return { ...model };
         case "NewMessage":
-            model.messages.push(msg["content"]);
+            model.messages.push(formatChat(msg.message));
             return { ...model };
-        case "ChatsFromTodayResponse":
-            model.messages.push(...msg.messages);
+        case "HistoryResponse":
+            model.messages.unshift(...msg.messages.map(formatChat));
             return { ...model };
     }
 }
@@ -80,6 +112,7 @@ export const InChatroom: React.FC<{
             let message: ClientToServerMessage = {
                 type: "NewMessage",
                 content: input,
+                idempotency_key: crypto.randomUUID(),
             };
             websocket.send(JSON.stringify(message));
             setInput("");
@@ -106,7 +139,7 @@ export const InChatroom: React.FC<{
                 chatroom_id: chatroom.chatroom_id,
             };
             websocket.send(JSON.stringify(message));
-            message = { type: "ChatsFromTodayRequest" };
+            message = { type: "HistoryRequest", before: null, limit: 50 };
             websocket.send(JSON.stringify(message));
         };
         websocket.onclose = () => {
@@ -119,9 +152,18 @@ export const InChatroom: React.FC<{
                 case "NewUser":
                 case "UserDisconnected":
                 case "NewMessage":
-                case "ChatsFromTodayResponse":
+                case "HistoryResponse":
                     chatroomDispatch(message);
                     return;
+                case "MessageAck":
+                    // Messages are shown once they are broadcast back to us.
+                    return;
+                case "Reconnect":
+                case "Moved":
+                case "Redirect":
+                    // The server closes the connection right after, which shows the chatroom
+                    // as disconnected.
+                    return;
                 default:
                     dispatch({
                         type: "Error",
@@ -20,9 +20,10 @@ export const Initial: React.FC<{
 
         fetch(url).then(async (response) => {
             if (response.ok && response.status == 200) {
+                let body = await response.json();
                 dispatch({
                     type: "ChatroomsResponse",
-                    chatrooms: await response.json(),
+                    chatrooms: body.chatrooms,
                 });
             } else {
                 dispatch({
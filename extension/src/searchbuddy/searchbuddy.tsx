@@ -12,6 +12,8 @@ export interface Chatroom {
     online: boolean;
     term: string;
     url: string;
+    kind: "word" | "phrase" | "query" | "related";
+    recent_messages: number;
 }
 
 type State =
@@ -10,7 +10,9 @@ export const SelectChatrooms: React.FC<{
             <thead>
                 <tr>
                     <th>Term</th>
+                    <th>Kind</th>
                     <th>Users</th>
+                    <th>Recent messages</th>
                     <th>Actions</th>
                 </tr>
             </thead>
@@ -43,7 +45,9 @@ const Row: React.FC<{ dispatch: React.Dispatch<Msg>; chatroom: Chatroom }> = ({
     return (
         <tr>
             <td>{chatroom.term}</td>
+            <td>{chatroom.kind}</td>
             <td>{chatroom.num_users}</td>
+            <td>{chatroom.recent_messages}</td>
             <td>
                 <button onClick={onClickConnect}>Connect</button>
             </td>
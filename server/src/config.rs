@@ -0,0 +1,55 @@
+use crate::query::Language;
+use serde::Deserialize;
+use shared::config::Storage;
+use shared::migration::Replication;
+use std::net::SocketAddr;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The address the server listens on.
+    pub bind_address: SocketAddr,
+    pub discovery_url: String,
+    /// The region assumed for clients that don't name one. Discovery decides when it is not
+    /// set.
+    pub region: Option<String>,
+    /// The language assumed for searches that don't name one. Not called `language`, since
+    /// the `LANGUAGE` variable is set by gettext on many hosts.
+    pub default_language: Language,
+    /// The most chatrooms returned for a search that doesn't set a limit.
+    pub chatroom_limit: usize,
+    /// How long a search waits for discovery, each chatroom instance and the store before
+    /// giving up on them.
+    pub request_timeout_ms: u64,
+    /// The most related chatrooms looked up for a search.
+    pub related_chatrooms: usize,
+    /// How many searches must have paired two words before one is suggested for the other.
+    pub related_min_searches: i64,
+    pub storage: Storage,
+    /// Whitespace separated list of ScyllaDB nodes.
+    pub scylla_url: Option<String>,
+    /// The replication of the keyspace. Changing it alters the keyspace on the next migration.
+    pub replication: Replication,
+    /// Whether to apply schema migrations on startup. When disabled they are applied by
+    /// running the server with the migrate subcommand.
+    pub migrate_on_startup: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: "0.0.0.0:8080".parse().unwrap(),
+            discovery_url: "http://discovery.gerber.website:8081".to_string(),
+            region: None,
+            default_language: Language::English,
+            chatroom_limit: 20,
+            request_timeout_ms: 2000,
+            related_chatrooms: 5,
+            related_min_searches: 3,
+            storage: Storage::Scylla,
+            scylla_url: None,
+            replication: Replication::default(),
+            migrate_on_startup: true,
+        }
+    }
+}
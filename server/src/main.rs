@@ -1,97 +1,223 @@
-#![feature(try_blocks)]
-
-use axum::extract::Query;
+use crate::config::Config;
+use crate::query::{extract_terms, Language};
+use crate::related::Recorder;
+use crate::store::{open_store, Store};
+use axum::extract::{Extension, Query};
 use axum::routing::get;
-use axum::{Json, Router};
+use axum::{AddExtensionLayer, Json, Router};
+use futures::future::join_all;
 use log::{error, info};
 use serde::Deserialize;
-use shared::discovery::{ChatroomRequest, ChatroomResponse};
-use shared::{initialize_logger, Chatroom};
+use shared::discovery::{BatchChatroomRequest, BatchChatroomResponse};
+use shared::{
+    initialize_logger, Chatroom, ChatroomActivity, ChatroomKind, ChatroomsResponse, LookupStatus,
+    TermStatus,
+};
 use std::collections::HashMap;
 use std::error::Error;
 use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
+mod config;
+mod query;
+mod ranking;
+mod related;
+mod store;
+
 type BoxError = Box<dyn Error + Send + Sync>;
 type BoxResult<T> = Result<T, BoxError>;
 
 #[derive(Debug, Deserialize)]
 struct ChatroomQuery {
     search: String,
+    /// The region the client prefers to chat in.
+    region: Option<String>,
+    /// The language of the search.
+    language: Option<Language>,
+    /// The most chatrooms to return.
+    limit: Option<usize>,
+    /// Leaves out chatrooms with fewer users than this.
+    min_users: Option<u32>,
 }
 
-async fn get_chatrooms(Query(query): Query<ChatroomQuery>) -> Json<Vec<Chatroom>> {
+async fn get_chatrooms(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(store): Extension<Arc<dyn Store>>,
+    Extension(recorder): Extension<Arc<Recorder>>,
+    Extension(client): Extension<reqwest::Client>,
+    Query(query): Query<ChatroomQuery>,
+) -> Json<ChatroomsResponse> {
     info!("GET /chatrooms: {:?}", query);
 
-    let terms: Vec<&str> = query.search.split(" ").collect();
+    let language = query.language.unwrap_or(config.default_language);
+    let terms = extract_terms(&query.search, language);
+    if terms.is_empty() {
+        return Json(ChatroomsResponse {
+            chatrooms: Vec::new(),
+            terms: Vec::new(),
+        });
+    }
+
+    // The first term is the whole search.
+    let query_words = terms[0].text.split(' ').count();
 
-    let instances = locate_instances(&terms).await;
+    let words: Vec<String> = terms
+        .iter()
+        .filter(|term| term.is_word())
+        .map(|term| term.text.clone())
+        .collect();
 
-    let client = reqwest::Client::new();
+    let timeout = Duration::from_millis(config.request_timeout_ms);
 
-    let mut chatrooms = Vec::new();
+    let related = tokio::time::timeout(
+        timeout,
+        related::find_related(
+            store.as_ref(),
+            &words,
+            config.related_min_searches,
+            config.related_chatrooms,
+        ),
+    )
+    .await
+    .unwrap_or_else(|_elapsed| Err("Timed out.".into()))
+    .unwrap_or_else(|error| {
+        error!("Failed to look up related terms - {:?}", error);
+        Vec::new()
+    });
+
+    recorder.record(words);
+
+    // The position of each term in the list, from most to least specific, and its kind.
+    let mut kinds: HashMap<String, (usize, ChatroomKind)> = terms
+        .iter()
+        .enumerate()
+        .map(|(index, term)| (term.text.clone(), (index, term.kind)))
+        .collect();
+    for term in related {
+        kinds.insert(term, (kinds.len(), ChatroomKind::Related));
+    }
+
+    let terms: Vec<String> = kinds.keys().cloned().collect();
+
+    // Terms discovery doesn't map to an instance keep this status.
+    let mut statuses: HashMap<String, LookupStatus> = terms
+        .iter()
+        .map(|term| (term.clone(), LookupStatus::NoInstanceAvailable))
+        .collect();
 
-    for (address, terms) in instances {
-        let response: BoxResult<HashMap<String, (i32, u32)>> = try {
-            client
-                .post(format!("http://{}/chatrooms", address))
-                .json(&terms)
-                .send()
-                .await?
-                .json::<HashMap<String, (i32, u32)>>()
-                .await?
-        };
+    let region = query.region.clone().or_else(|| config.region.clone());
+    let instances = match locate_instances(&client, &config, &terms, region).await {
+        Ok(instances) => instances,
+        Err(error) => {
+            error!("Failed to locate the instances of the terms - {:?}", error);
 
+            for status in statuses.values_mut() {
+                *status = LookupStatus::DiscoveryUnreachable;
+            }
+            HashMap::new()
+        }
+    };
+
+    let responses = join_all(instances.into_iter().map(|(address, terms)| {
+        let client = &client;
+        async move {
+            let response = query_instance(client, address, &terms).await;
+            (address, terms, response)
+        }
+    }))
+    .await;
+
+    let mut chatrooms = Vec::new();
+
+    for (address, terms, response) in responses {
         match response {
             Ok(response) => {
-                for (term, (chatroom_id, count)) in response {
+                for (term, activity) in response {
+                    let kind = match kinds.get(&term) {
+                        Some((_, kind)) => *kind,
+                        None => continue,
+                    };
+
+                    statuses.insert(term.clone(), LookupStatus::Ok);
                     chatrooms.push(Chatroom {
                         term,
                         online: true,
-                        chatroom_id,
-                        num_users: count,
+                        chatroom_id: activity.chatroom_id,
+                        num_users: activity.num_users,
                         url: format!("ws://{}/ws", address),
+                        kind,
+                        recent_messages: activity.recent_messages,
                     });
                 }
             }
             Err(error) => {
-                error!("Error occurred while querying chatroom instance {}", error);
+                error!(
+                    "Error occurred while querying chatroom instance {} - {:?}",
+                    address, error
+                );
+
+                for term in terms {
+                    statuses.insert(term, LookupStatus::InstanceUnreachable);
+                }
             }
         }
     }
 
-    Json(chatrooms)
+    // Related chatrooms are only worth suggesting when there is someone to talk to.
+    chatrooms.retain(|chatroom| chatroom.kind != ChatroomKind::Related || chatroom.num_users > 0);
+
+    // Chatrooms with the same score are ranked closest to what was searched for first.
+    chatrooms.sort_by_key(|chatroom| kinds[&chatroom.term].0);
+
+    let min_users = query.min_users.unwrap_or(0);
+    let limit = query.limit.unwrap_or(config.chatroom_limit);
+
+    let chatrooms = ranking::rank(chatrooms, query_words, min_users, limit);
+
+    let mut terms: Vec<TermStatus> = statuses
+        .into_iter()
+        .map(|(term, status)| TermStatus {
+            kind: kinds[&term].1,
+            term,
+            status,
+        })
+        .collect();
+    terms.sort_by_key(|status| kinds[&status.term].0);
+
+    Json(ChatroomsResponse { chatrooms, terms })
 }
 
-async fn locate_instances(terms: &[&str]) -> HashMap<SocketAddrV4, Vec<String>> {
+/// Returns the instance each term is mapped to, grouped by instance. Terms discovery couldn't
+/// map are left out.
+async fn locate_instances(
+    client: &reqwest::Client,
+    config: &Config,
+    terms: &[String],
+    region: Option<String>,
+) -> BoxResult<HashMap<SocketAddrV4, Vec<String>>> {
     let mut locations: HashMap<SocketAddrV4, Vec<String>> = HashMap::new();
 
-    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/chatrooms/batch", config.discovery_url))
+        .json(&BatchChatroomRequest {
+            terms: terms.to_vec(),
+            region,
+        })
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<BatchChatroomResponse>()
+        .await?;
 
     for term in terms {
-        let response = client
-            // .post("http://localhost:8081/chatroom")
-            .post("http://discovery.gerber.website:8081/chatroom")
-            .json(&ChatroomRequest {
-                term: term.to_string(),
-            })
-            .send()
-            .await
-            .expect("Failed to contact discovery service.")
-            .json::<ChatroomResponse>()
-            .await
-            .expect("Invalid response from discovery service.");
-
-        match response.instance {
+        match response.instances.get(term) {
             Some(instance) => {
-                let terms = locations.get_mut(&instance.address);
-
-                match terms {
-                    Some(terms) => terms.push(term.to_string()),
-                    None => {
-                        locations.insert(instance.address, vec![term.to_string()]);
-                    }
-                };
+                locations
+                    .entry(instance.address)
+                    .or_default()
+                    .push(term.to_string());
             }
             None => {
                 error!("A mapping could not be found for {}.", term);
@@ -99,22 +225,62 @@ async fn locate_instances(terms: &[&str]) -> HashMap<SocketAddrV4, Vec<String>>
         }
     }
 
-    locations
+    Ok(locations)
+}
+
+/// Returns the activity of the chatrooms of the terms on an instance.
+async fn query_instance(
+    client: &reqwest::Client,
+    address: SocketAddrV4,
+    terms: &[String],
+) -> BoxResult<HashMap<String, ChatroomActivity>> {
+    let response = client
+        .post(format!("http://{}/chatrooms", address))
+        .json(&terms)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<HashMap<String, ChatroomActivity>>()
+        .await?;
+
+    Ok(response)
+}
+
+async fn async_main(config: Config) -> BoxResult<()> {
+    let bind_address = config.bind_address;
+    let store: Arc<dyn Store> = Arc::from(open_store(&config).await?);
+    let recorder = Arc::new(Recorder::new(store.clone()));
+
+    // Shared by every request so that connections to discovery and the instances are reused.
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(config.request_timeout_ms))
+        .build()?;
+
+    let app = Router::new()
+        .route("/chatrooms", get(get_chatrooms))
+        .layer(AddExtensionLayer::new(Arc::new(config)))
+        .layer(AddExtensionLayer::new(recorder))
+        .layer(AddExtensionLayer::new(store))
+        .layer(AddExtensionLayer::new(client));
+
+    axum::Server::bind(&bind_address)
+        .serve(app.into_make_service())
+        .await?;
+
+    Ok(())
 }
 
 fn main() -> BoxResult<()> {
     initialize_logger()?;
 
-    let runtime = Runtime::new()?;
-
-    let app = Router::new().route("/chatrooms", get(get_chatrooms));
+    let config: Config = shared::config::load()?;
 
-    runtime.block_on(async {
-        axum::Server::bind(&"0.0.0.0:8080".parse().unwrap())
-            .serve(app.into_make_service())
-            .await
-            .unwrap();
-    });
+    let runtime = Runtime::new()?;
 
+    match shared::config::subcommand().as_deref() {
+        None => runtime.block_on(async_main(config))?,
+        Some("migrate") => runtime.block_on(store::migrate(&config))?,
+        Some(command) => return Err(format!("Unknown command {}.", command).into()),
+    }
     Ok(())
 }
@@ -0,0 +1,237 @@
+// Searches are turned into terms so that people searching for the same thing end up in the
+// same chatrooms, even when they word it slightly differently. A search is case folded, split
+// into words on anything that isn't a letter or digit, stripped of stopwords and stemmed. The
+// remaining words together are a term, which is the chatroom for the whole question. So is each
+// run of up to MAX_PHRASE_WORDS adjacent words, which gives phrases such as "borrow checker" a
+// chatroom of their own, and each word by itself.
+
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::Deserialize;
+use shared::ChatroomKind;
+
+/// The longest phrase taken from a search, other than the whole search.
+const MAX_PHRASE_WORDS: usize = 3;
+
+/// The language a search is written in, which decides its stopwords and how words are stemmed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    English,
+    French,
+    German,
+    Spanish,
+}
+
+const ENGLISH_STOPWORDS: &[&str] = &[
+    "a", "about", "after", "all", "am", "an", "and", "any", "are", "as", "at", "be", "because",
+    "been", "before", "being", "but", "by", "can", "could", "did", "do", "does", "doing", "for",
+    "from", "had", "has", "have", "having", "he", "her", "here", "hers", "him", "his", "how", "i",
+    "if", "in", "into", "is", "it", "its", "just", "me", "my", "no", "not", "of", "on", "or",
+    "our", "ours", "she", "so", "some", "than", "that", "the", "their", "them", "then", "there",
+    "these", "they", "this", "those", "to", "too", "up", "us", "very", "was", "we", "were", "what",
+    "when", "where", "which", "while", "who", "whom", "why", "will", "with", "would", "you",
+    "your", "yours",
+];
+
+const FRENCH_STOPWORDS: &[&str] = &[
+    "a", "au", "aux", "avec", "ce", "ces", "comment", "dans", "de", "des", "du", "elle", "en",
+    "est", "et", "il", "je", "la", "le", "les", "leur", "lui", "ma", "mais", "me", "mes", "mon",
+    "ne", "nous", "on", "ou", "par", "pas", "pour", "qu", "que", "qui", "sa", "se", "ses", "son",
+    "sur", "ta", "te", "tes", "ton", "tu", "un", "une", "vous",
+];
+
+const GERMAN_STOPWORDS: &[&str] = &[
+    "aber", "als", "am", "an", "auch", "auf", "aus", "bei", "bin", "bis", "das", "dass", "dem",
+    "den", "der", "des", "die", "du", "ein", "eine", "einem", "einen", "einer", "er", "es", "für",
+    "hat", "ich", "im", "in", "ist", "mit", "nach", "nicht", "oder", "sie", "sind", "so", "und",
+    "von", "vor", "was", "wie", "wir", "zu", "zum", "zur",
+];
+
+const SPANISH_STOPWORDS: &[&str] = &[
+    "a", "al", "como", "con", "de", "del", "el", "ella", "en", "es", "esta", "este", "la", "las",
+    "le", "lo", "los", "me", "mi", "no", "o", "para", "pero", "por", "que", "se", "si", "su",
+    "sus", "te", "tu", "un", "una", "y", "yo",
+];
+
+impl Language {
+    fn stopwords(&self) -> &'static [&'static str] {
+        match self {
+            Language::English => ENGLISH_STOPWORDS,
+            Language::French => FRENCH_STOPWORDS,
+            Language::German => GERMAN_STOPWORDS,
+            Language::Spanish => SPANISH_STOPWORDS,
+        }
+    }
+
+    fn stemmer(&self) -> Stemmer {
+        let algorithm = match self {
+            Language::English => Algorithm::English,
+            Language::French => Algorithm::French,
+            Language::German => Algorithm::German,
+            Language::Spanish => Algorithm::Spanish,
+        };
+
+        Stemmer::create(algorithm)
+    }
+}
+
+/// A term extracted from a search. The words of a term are separated by a space.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Term {
+    pub text: String,
+    pub kind: ChatroomKind,
+}
+
+impl Term {
+    pub fn is_word(&self) -> bool {
+        !self.text.contains(' ')
+    }
+}
+
+/// Splits a search into case folded words, without punctuation. Apostrophes are dropped rather
+/// than split on, so "don't" and "dont" are the same word.
+fn split_words(search: &str) -> Vec<String> {
+    let folded = caseless::default_case_fold_str(search);
+
+    folded
+        .split(|char: char| !char.is_alphanumeric() && char != '\'' && char != '’')
+        .map(|word| word.replace(['\'', '’'], ""))
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Returns the terms of a search without duplicates, most specific first: the whole search,
+/// then phrases from longest to shortest, then words. Terms of the same length are in the order
+/// they appeared.
+pub fn extract_terms(search: &str, language: Language) -> Vec<Term> {
+    let words = split_words(search);
+
+    let stopwords = language.stopwords();
+    let mut kept: Vec<&String> = words
+        .iter()
+        .filter(|word| !stopwords.contains(&word.as_str()))
+        .collect();
+
+    // A search made only of stopwords, such as "the who", is still worth a chatroom.
+    if kept.is_empty() {
+        kept = words.iter().collect();
+    }
+
+    let stemmer = language.stemmer();
+    let stems: Vec<String> = kept
+        .iter()
+        .map(|word| stemmer.stem(word).into_owned())
+        .collect();
+
+    let mut terms: Vec<Term> = Vec::new();
+    if stems.is_empty() {
+        return terms;
+    }
+
+    terms.push(Term {
+        text: stems.join(" "),
+        kind: ChatroomKind::Query,
+    });
+
+    let longest_phrase = MAX_PHRASE_WORDS.min(stems.len() - 1);
+    for length in (1..=longest_phrase).rev() {
+        let kind = match length {
+            1 => ChatroomKind::Word,
+            _ => ChatroomKind::Phrase,
+        };
+
+        for window in stems.windows(length) {
+            let text = window.join(" ");
+
+            // The search itself, or a phrase repeated within it.
+            if terms.iter().any(|term| term.text == text) {
+                continue;
+            }
+
+            terms.push(Term { text, kind });
+        }
+    }
+
+    terms
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::{extract_terms, Language};
+    use shared::ChatroomKind;
+
+    fn texts(search: &str, language: Language, kind: ChatroomKind) -> Vec<String> {
+        extract_terms(search, language)
+            .into_iter()
+            .filter(|term| term.kind == kind)
+            .map(|term| term.text)
+            .collect()
+    }
+
+    #[test]
+    fn different_wordings_share_terms() {
+        let expected = extract_terms("how to learn rust", Language::English);
+
+        for search in [
+            "How To Learn Rust",
+            "how  to learn rust!",
+            "  HOW TO, learn... Rust?",
+        ] {
+            assert_eq!(extract_terms(search, Language::English), expected);
+        }
+
+        let words = texts("how to learn rust", Language::English, ChatroomKind::Word);
+        assert_eq!(words, vec!["learn", "rust"]);
+
+        // Stemming puts different forms of a word in the same chatroom.
+        let words = texts("learning rust", Language::English, ChatroomKind::Word);
+        assert_eq!(words, vec!["learn", "rust"]);
+    }
+
+    #[test]
+    fn stopwords_depend_on_the_language() {
+        let words = texts("le chat et la souris", Language::French, ChatroomKind::Word);
+        assert_eq!(words, vec!["chat", "sour"]);
+
+        let query = texts("the who rocks", Language::English, ChatroomKind::Query);
+        assert_eq!(query, vec!["rock"]);
+
+        // A search made only of stopwords keeps them.
+        let query = texts("the who", Language::English, ChatroomKind::Query);
+        assert_eq!(query, vec!["the who"]);
+
+        assert!(extract_terms(" ?! ", Language::English).is_empty());
+    }
+
+    #[test]
+    fn terms_are_ordered_by_specificity() {
+        let terms: Vec<(String, ChatroomKind)> =
+            extract_terms("rust borrow checker error", Language::English)
+                .into_iter()
+                .map(|term| (term.text, term.kind))
+                .collect();
+
+        let expected = [
+            ("rust borrow checker error", ChatroomKind::Query),
+            ("rust borrow checker", ChatroomKind::Phrase),
+            ("borrow checker error", ChatroomKind::Phrase),
+            ("rust borrow", ChatroomKind::Phrase),
+            ("borrow checker", ChatroomKind::Phrase),
+            ("checker error", ChatroomKind::Phrase),
+            ("rust", ChatroomKind::Word),
+            ("borrow", ChatroomKind::Word),
+            ("checker", ChatroomKind::Word),
+            ("error", ChatroomKind::Word),
+        ];
+        let expected: Vec<(String, ChatroomKind)> = expected
+            .iter()
+            .map(|(text, kind)| (text.to_string(), *kind))
+            .collect();
+        assert_eq!(terms, expected);
+
+        // A single word is the whole search.
+        let terms = extract_terms("Rust", Language::English);
+        assert_eq!(terms.len(), 1);
+        assert_eq!(terms[0].kind, ChatroomKind::Query);
+    }
+}
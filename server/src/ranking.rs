@@ -0,0 +1,118 @@
+// Chatrooms are ranked by how likely they are to be worth joining. People are what make a
+// chatroom worth joining, so the number of users and recent messages count the most, but both
+// are counted on a log scale: a chatroom with a hundred people isn't a hundred times better
+// than one with a single person. A chatroom also scores higher the closer its term is to the
+// whole search, and the chatroom of the whole search gets a bonus on top of that. Related
+// chatrooms aren't part of the search at all, so they only score on their users and activity.
+
+use shared::{Chatroom, ChatroomKind};
+use std::cmp::Ordering;
+
+const USERS_WEIGHT: f64 = 1.0;
+const ACTIVITY_WEIGHT: f64 = 0.5;
+const SPECIFICITY_WEIGHT: f64 = 1.0;
+const EXACT_QUERY_BONUS: f64 = 0.5;
+
+/// Returns the score of a chatroom found for a search of `query_words` words.
+pub fn score(chatroom: &Chatroom, query_words: usize) -> f64 {
+    let users = (1.0 + chatroom.num_users as f64).ln();
+    let activity = (1.0 + chatroom.recent_messages as f64).ln();
+
+    let term_words = chatroom.term.split(' ').count();
+    let specificity = match chatroom.kind {
+        ChatroomKind::Related => 0.0,
+        _ => term_words as f64 / query_words.max(term_words) as f64,
+    };
+
+    let exact_query = match chatroom.kind {
+        ChatroomKind::Query => EXACT_QUERY_BONUS,
+        _ => 0.0,
+    };
+
+    USERS_WEIGHT * users
+        + ACTIVITY_WEIGHT * activity
+        + SPECIFICITY_WEIGHT * specificity
+        + exact_query
+}
+
+/// Drops the chatrooms with fewer than `min_users` users and returns the best `limit` of the
+/// rest, best first. Chatrooms with the same score keep their order.
+pub fn rank(
+    chatrooms: Vec<Chatroom>,
+    query_words: usize,
+    min_users: u32,
+    limit: usize,
+) -> Vec<Chatroom> {
+    let mut scored: Vec<(f64, Chatroom)> = chatrooms
+        .into_iter()
+        .filter(|chatroom| chatroom.num_users >= min_users)
+        .map(|chatroom| (score(&chatroom, query_words), chatroom))
+        .collect();
+
+    scored.sort_by(|(first, _), (second, _)| second.partial_cmp(first).unwrap_or(Ordering::Equal));
+
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_score, chatroom)| chatroom)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ranking::rank;
+    use shared::{Chatroom, ChatroomKind};
+
+    fn chatroom(term: &str, kind: ChatroomKind, num_users: u32, recent_messages: u32) -> Chatroom {
+        Chatroom {
+            chatroom_id: 0,
+            num_users,
+            online: true,
+            term: term.to_string(),
+            url: String::new(),
+            kind,
+            recent_messages,
+        }
+    }
+
+    fn terms(chatrooms: &[Chatroom]) -> Vec<&str> {
+        chatrooms
+            .iter()
+            .map(|chatroom| chatroom.term.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn busy_and_specific_chatrooms_come_first() {
+        let chatrooms = vec![
+            chatroom("rust", ChatroomKind::Word, 0, 0),
+            chatroom("learn", ChatroomKind::Word, 20, 50),
+            chatroom("learn rust", ChatroomKind::Query, 0, 0),
+            chatroom("async", ChatroomKind::Word, 2, 0),
+        ];
+
+        let ranked = rank(chatrooms, 2, 0, 10);
+        assert_eq!(terms(&ranked), vec!["learn", "async", "learn rust", "rust"]);
+
+        // With nobody anywhere, the chatroom of the whole search is the best place to wait.
+        let chatrooms = vec![
+            chatroom("rust", ChatroomKind::Word, 0, 0),
+            chatroom("learn rust", ChatroomKind::Query, 0, 0),
+        ];
+        let ranked = rank(chatrooms, 2, 0, 10);
+        assert_eq!(terms(&ranked), vec!["learn rust", "rust"]);
+    }
+
+    #[test]
+    fn chatrooms_are_filtered_and_limited() {
+        let chatrooms = vec![
+            chatroom("rust", ChatroomKind::Word, 1, 0),
+            chatroom("learn", ChatroomKind::Word, 3, 0),
+            chatroom("learn rust", ChatroomKind::Query, 5, 0),
+            chatroom("async", ChatroomKind::Word, 2, 0),
+        ];
+
+        let ranked = rank(chatrooms, 2, 2, 2);
+        assert_eq!(terms(&ranked), vec!["learn rust", "learn"]);
+    }
+}
@@ -0,0 +1,161 @@
+// People who search for "tokio" often search for "async" too. The words of every search are
+// counted in pairs, and the words most often searched for together with those of a search
+// lead to related chatrooms, which may have people in them when the chatrooms of the search
+// itself are empty.
+//
+// Only pairs of stemmed words are counted, never whole searches or who made them, and a word
+// is only suggested once enough searches paired it, so a single person's searches can't be
+// read back from the suggestions.
+
+use crate::store::Store;
+use crate::BoxResult;
+use futures::future::join_all;
+use log::error;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// The most words of a search counted in pairs. Every pair is a write, so longer searches
+/// only have their first words counted.
+const MAX_RECORDED_WORDS: usize = 8;
+
+/// The most words paired with each word of a search that are considered for suggestions.
+const MAX_COOCCURRENCES: usize = 100;
+
+/// The most searches being counted at once. Searches beyond that aren't counted.
+const MAX_PENDING_RECORDINGS: usize = 64;
+
+/// Counts the words of a search as searched for together.
+pub async fn record_search(store: &dyn Store, words: &[String]) -> BoxResult<()> {
+    let words = &words[..words.len().min(MAX_RECORDED_WORDS)];
+    if words.len() < 2 {
+        return Ok(());
+    }
+
+    store.record_cooccurrences(words).await
+}
+
+/// Counts searches in the background so searches don't wait on the store.
+pub struct Recorder {
+    store: Arc<dyn Store>,
+    permits: Arc<Semaphore>,
+}
+
+impl Recorder {
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        Recorder {
+            store,
+            permits: Arc::new(Semaphore::new(MAX_PENDING_RECORDINGS)),
+        }
+    }
+
+    /// Starts counting the words of a search. When the store falls behind the search is not
+    /// counted, rather than piling up tasks waiting on it. Suggestions only need rough counts.
+    pub fn record(&self, words: Vec<String>) {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_error) => return,
+        };
+        let store = self.store.clone();
+
+        tokio::spawn(async move {
+            if let Err(error) = record_search(store.as_ref(), &words).await {
+                error!("Failed to record search - {:?}", error);
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Returns up to `max` words most often searched for together with the words of a search,
+/// leaving out the words themselves and those paired fewer than `min_searches` times.
+pub async fn find_related(
+    store: &dyn Store,
+    words: &[String],
+    min_searches: i64,
+    max: usize,
+) -> BoxResult<Vec<String>> {
+    let cooccurrences = join_all(
+        words
+            .iter()
+            .map(|word| store.get_cooccurrences(word, MAX_COOCCURRENCES)),
+    )
+    .await;
+
+    let mut totals: HashMap<String, i64> = HashMap::new();
+
+    for cooccurrences in cooccurrences {
+        for (other, count) in cooccurrences? {
+            if count >= min_searches && !words.contains(&other) {
+                *totals.entry(other).or_default() += count;
+            }
+        }
+    }
+
+    let mut related: Vec<(String, i64)> = totals.into_iter().collect();
+    // Ties are broken by the word so the suggestions are stable.
+    related.sort_by(|(first, first_count), (second, second_count)| {
+        second_count
+            .cmp(first_count)
+            .then_with(|| first.cmp(second))
+    });
+
+    Ok(related
+        .into_iter()
+        .take(max)
+        .map(|(word, _count)| word)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::related::{find_related, record_search};
+    use crate::store::{MemoryStore, Store};
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn words_searched_together_are_related() {
+        let store = MemoryStore::new();
+
+        for _ in 0..3 {
+            record_search(&store, &words(&["tokio", "async"]))
+                .await
+                .unwrap();
+        }
+        for _ in 0..2 {
+            record_search(&store, &words(&["tokio", "runtim"]))
+                .await
+                .unwrap();
+        }
+        record_search(&store, &words(&["tokio", "secret"]))
+            .await
+            .unwrap();
+        record_search(&store, &words(&["tokio"])).await.unwrap();
+
+        let related = find_related(&store, &words(&["tokio"]), 2, 5)
+            .await
+            .unwrap();
+        assert_eq!(related, words(&["async", "runtim"]));
+
+        let related = find_related(&store, &words(&["tokio"]), 2, 1)
+            .await
+            .unwrap();
+        assert_eq!(related, words(&["async"]));
+
+        // Only the words paired most often are read back.
+        let counts = store.get_cooccurrences("tokio", 2).await.unwrap();
+        assert_eq!(
+            counts,
+            vec![("async".to_string(), 3), ("runtim".to_string(), 2)]
+        );
+
+        // Words of the search itself are never suggested.
+        let related = find_related(&store, &words(&["async", "tokio"]), 1, 5)
+            .await
+            .unwrap();
+        assert_eq!(related, words(&["runtim", "secret"]));
+    }
+}
@@ -0,0 +1,57 @@
+use crate::config::Config;
+use crate::BoxResult;
+use async_trait::async_trait;
+use log::info;
+use shared::config::Storage;
+
+mod memory;
+mod scylla;
+
+pub use self::memory::MemoryStore;
+pub use self::scylla::ScyllaStore;
+
+/// Persistence used by the frontend server. Only counts of how often two terms were searched
+/// for together are kept, never the searches themselves or who made them.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Counts every pair of the terms as searched for together once more.
+    async fn record_cooccurrences(&self, terms: &[String]) -> BoxResult<()>;
+
+    /// Returns the `limit` terms searched for together with `term` most often and how many
+    /// times they were, most often first.
+    async fn get_cooccurrences(&self, term: &str, limit: usize) -> BoxResult<Vec<(String, i64)>>;
+}
+
+/// Opens the store selected by the configuration.
+pub async fn open_store(config: &Config) -> BoxResult<Box<dyn Store>> {
+    match config.storage {
+        Storage::Scylla => {
+            let scylla_urls = config
+                .scylla_url
+                .as_deref()
+                .ok_or("SCYLLA_URL not defined.")?;
+            Ok(Box::new(ScyllaStore::new(scylla_urls, config).await?))
+        }
+        Storage::Memory => {
+            info!("Using in-memory storage. Related searches will not survive a restart.");
+            Ok(Box::new(MemoryStore::new()))
+        }
+    }
+}
+
+/// Applies the schema migrations of the store selected by the configuration.
+pub async fn migrate(config: &Config) -> BoxResult<()> {
+    match config.storage {
+        Storage::Scylla => {
+            let scylla_urls = config
+                .scylla_url
+                .as_deref()
+                .ok_or("SCYLLA_URL not defined.")?;
+            self::scylla::run_migrations(scylla_urls, config).await
+        }
+        Storage::Memory => {
+            info!("In-memory storage has no schema to migrate.");
+            Ok(())
+        }
+    }
+}
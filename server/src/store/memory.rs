@@ -0,0 +1,57 @@
+use crate::store::Store;
+use crate::BoxResult;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+/// A store that keeps the counts in process memory. Intended for local development and tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    // Maps a term to the terms searched for with it and how often.
+    cooccurrences: RwLock<HashMap<String, HashMap<String, i64>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn record_cooccurrences(&self, terms: &[String]) -> BoxResult<()> {
+        let mut cooccurrences = self.cooccurrences.write().await;
+
+        for term in terms {
+            let counts = cooccurrences.entry(term.clone()).or_default();
+
+            for other in terms.iter().filter(|other| *other != term) {
+                *counts.entry(other.clone()).or_default() += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_cooccurrences(&self, term: &str, limit: usize) -> BoxResult<Vec<(String, i64)>> {
+        let cooccurrences = self.cooccurrences.read().await;
+
+        let mut counts: Vec<(String, i64)> = match cooccurrences.get(term) {
+            Some(counts) => counts
+                .iter()
+                .map(|(other, count)| (other.clone(), *count))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // Mirrors the Scylla store, which keeps the first term in order among equal counts.
+        counts.sort_by(|(first, first_count), (second, second_count)| {
+            second_count
+                .cmp(first_count)
+                .then_with(|| first.cmp(second))
+        });
+        counts.truncate(limit);
+
+        Ok(counts)
+    }
+}
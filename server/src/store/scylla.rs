@@ -0,0 +1,138 @@
+use crate::config::Config;
+use crate::store::Store;
+use crate::BoxResult;
+use async_trait::async_trait;
+use futures::StreamExt;
+use scylla::batch::{Batch, BatchType};
+use scylla::frame::value::Counter;
+use scylla::query::Query;
+use scylla::{Session, SessionBuilder};
+use shared::migration::{self, Migration, Step};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+const KEYSPACE: &str = "searchbuddy";
+
+// How many rows are read per page when looking up the terms searched for with a term.
+const COOCCURRENCE_PAGE_SIZE: i32 = 1000;
+
+// The schema of the frontend server. Migrations are applied in order and each is recorded in
+// the schema_version table once applied, see shared::migration.
+static MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "Create the term_cooccurrence table",
+    steps: &[Step::Cql(
+        r#"
+        CREATE TABLE IF NOT EXISTS term_cooccurrence (
+            term text,
+            other text,
+            count counter,
+            PRIMARY KEY(term, other),
+        );
+        "#,
+    )],
+}];
+
+pub struct ScyllaStore {
+    session: Session,
+}
+
+impl ScyllaStore {
+    pub async fn new(scylla_urls: &str, config: &Config) -> BoxResult<Self> {
+        let session = connect(scylla_urls).await?;
+
+        if config.migrate_on_startup {
+            migrate(&session, config, false).await?;
+        } else {
+            session.use_keyspace(KEYSPACE, false).await?;
+        }
+
+        Ok(ScyllaStore { session })
+    }
+}
+
+async fn connect(scylla_urls: &str) -> BoxResult<Session> {
+    let scylla_urls: Vec<&str> = scylla_urls.split_whitespace().collect();
+
+    let session = SessionBuilder::new()
+        .known_nodes(&scylla_urls)
+        .build()
+        .await?;
+
+    Ok(session)
+}
+
+/// Applies the migrations, and the configured replication when `alter_replication` is set.
+async fn migrate(session: &Session, config: &Config, alter_replication: bool) -> BoxResult<()> {
+    migration::run(
+        session,
+        KEYSPACE,
+        "server",
+        &config.replication,
+        alter_replication,
+        MIGRATIONS,
+        &(),
+    )
+    .await
+}
+
+/// Applies the migrations of the frontend server without starting it.
+pub async fn run_migrations(scylla_urls: &str, config: &Config) -> BoxResult<()> {
+    let session = connect(scylla_urls).await?;
+    migrate(&session, config, true).await
+}
+
+#[async_trait]
+impl Store for ScyllaStore {
+    async fn record_cooccurrences(&self, terms: &[String]) -> BoxResult<()> {
+        // Counter updates can only be batched with other counter updates. The batch is sent
+        // as one request rather than one per pair.
+        let mut batch = Batch::new(BatchType::Counter);
+        let mut values = Vec::new();
+
+        for term in terms {
+            for other in terms.iter().filter(|other| *other != term) {
+                batch.append_statement(
+                    "UPDATE term_cooccurrence SET count = count + 1 WHERE term = ? AND other = ?",
+                );
+                values.push((term, other));
+            }
+        }
+
+        if !values.is_empty() {
+            self.session.batch(&batch, values).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_cooccurrences(&self, term: &str, limit: usize) -> BoxResult<Vec<(String, i64)>> {
+        // Rows are ordered by the other term rather than the count, so the partition is read a
+        // page at a time and only the most frequent terms are kept. Among equal counts the
+        // first term in order is kept.
+        let mut rows = self
+            .session
+            .query_iter(
+                Query::new("SELECT other, count FROM term_cooccurrence WHERE term = ?")
+                    .with_page_size(COOCCURRENCE_PAGE_SIZE),
+                (term,),
+            )
+            .await?
+            .into_typed::<(String, Counter)>();
+
+        let mut top = BinaryHeap::new();
+        while let Some(row) = rows.next().await {
+            let (other, Counter(count)) = row?;
+            top.push(Reverse((count, Reverse(other))));
+            if top.len() > limit {
+                top.pop();
+            }
+        }
+
+        Ok(top
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((count, Reverse(other)))| (other, count))
+            .collect())
+    }
+}
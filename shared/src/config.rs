@@ -0,0 +1,392 @@
+// Configuration is loaded into a struct defined by each binary. Every field of the struct can be
+// set from the following sources, where later sources override earlier ones:
+//
+// 1. The defaults of the struct, which must be marked with #[serde(default)].
+// 2. A TOML file named by the --config flag or the CONFIG_FILE environment variable.
+// 3. An environment variable with the field name in uppercase, e.g. DISCOVERY_URL.
+// 4. A command line flag with the field name in kebab case, e.g. --discovery-url.
+//
+// Values taken from the environment or flags are converted to the type of their field. String
+// fields take the value as it is, so REGION=123 stays a string, while numbers and booleans are
+// parsed. Lists and tables, e.g. REGIONS='["US1", "EU1"]', are written as TOML values.
+
+use serde::de::value::{MapDeserializer, StringDeserializer};
+use serde::de::{DeserializeOwned, Error as DeError, IntoDeserializer, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::{env, fs};
+use toml::value::Table;
+use toml::Value;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// The storage backend used by a service.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    Scylla,
+    Memory,
+}
+
+/// Loads the configuration of the current process from its arguments and environment.
+pub fn load<T: DeserializeOwned>() -> BoxResult<T> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if subcommand().is_some() {
+        args.remove(0);
+    }
+    load_from(&args, |key| env::var(key).ok())
+}
+
+/// Returns the subcommand the process was started with, which is its first argument when that
+/// isn't a flag.
+pub fn subcommand() -> Option<String> {
+    env::args().nth(1).filter(|arg| !arg.starts_with("--"))
+}
+
+fn load_from<T: DeserializeOwned>(
+    args: &[String],
+    env: impl Fn(&str) -> Option<String>,
+) -> BoxResult<T> {
+    let fields = field_names::<T>();
+    let flags = parse_flags(args)?;
+
+    let path = flags
+        .iter()
+        .find(|(key, _value)| key == "config")
+        .map(|(_key, value)| value.clone())
+        .or_else(|| env("CONFIG_FILE"));
+
+    let file = match path {
+        Some(path) => {
+            let contents = fs::read_to_string(&path)
+                .map_err(|error| format!("Failed to read config file {} - {}", path, error))?;
+            toml::from_str::<Table>(&contents)?
+        }
+        None => Table::new(),
+    };
+
+    let mut table: BTreeMap<String, Setting> = file
+        .into_iter()
+        .map(|(key, value)| (key, Setting::Toml(value)))
+        .collect();
+
+    for field in fields {
+        if let Some(value) = env(&field.to_uppercase()) {
+            table.insert(field.to_string(), Setting::Raw(value));
+        }
+    }
+
+    for (key, value) in flags {
+        if key == "config" {
+            continue;
+        }
+
+        if !fields.contains(&key.as_str()) {
+            return Err(format!("Unknown flag --{}.", key.replace('_', "-")).into());
+        }
+
+        table.insert(key, Setting::Raw(value));
+    }
+
+    let deserializer = MapDeserializer::<_, toml::de::Error>::new(table.into_iter());
+
+    Ok(T::deserialize(deserializer)?)
+}
+
+/// Parses `--key value` and `--key=value` pairs. A flag without a value is set to true.
+fn parse_flags(args: &[String]) -> BoxResult<Vec<(String, String)>> {
+    let mut flags = Vec::new();
+    let mut args = args.iter().peekable();
+
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| format!("Unexpected argument {}.", arg))?;
+
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key, value.to_string()),
+            None => match args.peek() {
+                Some(value) if !value.starts_with("--") => (flag, args.next().unwrap().clone()),
+                _ => (flag, "true".to_string()),
+            },
+        };
+
+        flags.push((key.replace('-', "_"), value));
+    }
+
+    Ok(flags)
+}
+
+/// A setting read from the config file, or given as text in the environment or a flag.
+enum Setting {
+    Toml(Value),
+    Raw(String),
+}
+
+impl<'de> IntoDeserializer<'de, toml::de::Error> for Setting {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl Setting {
+    fn parse<'de, T: FromStr, V: Visitor<'de>>(
+        raw: String,
+        visit: impl FnOnce(T) -> Result<V::Value, toml::de::Error>,
+    ) -> Result<V::Value, toml::de::Error> {
+        match raw.parse::<T>() {
+            Ok(value) => visit(value),
+            Err(_) => Err(toml::de::Error::custom(format!(
+                "invalid value {:?} for {}",
+                raw,
+                std::any::type_name::<T>()
+            ))),
+        }
+    }
+
+    /// Reads the raw text as a TOML value, for lists and tables.
+    fn toml(raw: String) -> Result<Value, toml::de::Error> {
+        let mut table = toml::from_str::<Table>(&format!("value = {}", raw))?;
+        Ok(table.remove("value").unwrap_or(Value::String(raw)))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $ty:ty, $visit:ident;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self {
+                    Setting::Toml(value) => value.$method(visitor),
+                    Setting::Raw(raw) => Self::parse::<$ty, V>(raw, |value| visitor.$visit(value)),
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! deserialize_as_toml {
+    ($($method:ident;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self {
+                    Setting::Toml(value) => value.$method(visitor),
+                    Setting::Raw(raw) => Self::toml(raw)?.$method(visitor),
+                }
+            }
+        )*
+    };
+}
+
+// Raw text is handed to string fields unchanged and parsed for every other type.
+impl<'de> Deserializer<'de> for Setting {
+    type Error = toml::de::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Setting::Toml(value) => value.deserialize_any(visitor),
+            Setting::Raw(raw) => visitor.visit_string(raw),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Setting::Toml(value) => value.deserialize_option(visitor),
+            Setting::Raw(raw) => visitor.visit_some(Setting::Raw(raw)),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Setting::Toml(value) => value.deserialize_newtype_struct(name, visitor),
+            Setting::Raw(raw) => visitor.visit_newtype_struct(Setting::Raw(raw)),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Setting::Toml(value) => value.deserialize_enum(name, variants, visitor),
+            Setting::Raw(raw) => {
+                let raw: StringDeserializer<Self::Error> = raw.into_deserializer();
+                raw.deserialize_enum(name, variants, visitor)
+            }
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Setting::Toml(value) => value.deserialize_struct(name, fields, visitor),
+            Setting::Raw(raw) => Self::toml(raw)?.deserialize_struct(name, fields, visitor),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => bool, visit_bool;
+        deserialize_i8 => i8, visit_i8;
+        deserialize_i16 => i16, visit_i16;
+        deserialize_i32 => i32, visit_i32;
+        deserialize_i64 => i64, visit_i64;
+        deserialize_u8 => u8, visit_u8;
+        deserialize_u16 => u16, visit_u16;
+        deserialize_u32 => u32, visit_u32;
+        deserialize_u64 => u64, visit_u64;
+        deserialize_f32 => f32, visit_f32;
+        deserialize_f64 => f64, visit_f64;
+        deserialize_char => char, visit_char;
+    }
+
+    deserialize_as_toml! {
+        deserialize_seq;
+        deserialize_map;
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct tuple tuple_struct identifier
+        ignored_any
+    }
+}
+
+/// Returns the names of the fields of a config struct.
+fn field_names<T: DeserializeOwned>() -> &'static [&'static str] {
+    // Serde only exposes the field names of a struct to the deserializer it is given, so the
+    // struct is deserialized from a deserializer that records them and then gives up.
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(Self::Error::custom("config must be a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(Self::Error::custom("field names recorded"))
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{load_from, Storage};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    #[derive(Deserialize)]
+    #[serde(default)]
+    struct Config {
+        discovery_url: String,
+        heartbeat_interval_secs: u64,
+        storage: Storage,
+        datacenter: String,
+        peers: Vec<String>,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                discovery_url: "http://localhost:8081".to_string(),
+                heartbeat_interval_secs: 2,
+                storage: Storage::Scylla,
+                datacenter: "dc1".to_string(),
+                peers: Vec::new(),
+            }
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn sources_are_layered() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "heartbeat_interval_secs = 5").unwrap();
+        writeln!(file, "storage = \"memory\"").unwrap();
+
+        let env: HashMap<&str, String> = [
+            ("CONFIG_FILE", path.display().to_string()),
+            ("HEARTBEAT_INTERVAL_SECS", "7".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        let config: Config = load_from(&args(&["--discovery-url=http://example.com"]), |key| {
+            env.get(key).cloned()
+        })
+        .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.discovery_url, "http://example.com");
+        assert_eq!(config.heartbeat_interval_secs, 7);
+        assert_eq!(config.storage, Storage::Memory);
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let result = load_from::<Config>(&args(&["--region", "EU1"]), |_| None);
+        assert!(result.is_err());
+
+        let config: Config =
+            load_from(&args(&["--heartbeat-interval-secs", "3"]), |_| None).unwrap();
+        assert_eq!(config.heartbeat_interval_secs, 3);
+        assert_eq!(config.discovery_url, "http://localhost:8081");
+    }
+
+    #[test]
+    fn values_take_the_type_of_their_field() {
+        let env: HashMap<&str, String> = [
+            ("DATACENTER", "123".to_string()),
+            ("PEERS", r#"["10.0.0.2:3001", "10.0.0.3:3001"]"#.to_string()),
+            ("STORAGE", "memory".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        let config: Config = load_from(&args(&[]), |key| env.get(key).cloned()).unwrap();
+        assert_eq!(config.datacenter, "123");
+        assert_eq!(config.peers, vec!["10.0.0.2:3001", "10.0.0.3:3001"]);
+        assert_eq!(config.storage, Storage::Memory);
+
+        let config: Config = load_from(&args(&["--datacenter", "1e3"]), |_| None).unwrap();
+        assert_eq!(config.datacenter, "1e3");
+
+        let result = load_from::<Config>(&args(&["--heartbeat-interval-secs", "1e3"]), |_| None);
+        assert!(result.is_err());
+    }
+}
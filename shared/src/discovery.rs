@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::SocketAddrV4;
 
 #[derive(Copy, Clone, Deserialize, Serialize)]
@@ -7,14 +8,29 @@ pub struct Instance {
     pub address: SocketAddrV4,
 }
 
-#[derive(Copy, Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct RegisterRequest {
     pub address: SocketAddrV4,
+    /// The region the instance runs in. Discovery assumes its default region when missing.
+    #[serde(default)]
+    pub region: Option<String>,
 }
 
 #[derive(Copy, Clone, Deserialize, Serialize)]
 pub struct RegisterResponse {
     pub instance_id: i32,
+    pub lease: Lease,
+}
+
+/// The right of an instance to serve the terms mapped to it. A lease runs out `duration_ms`
+/// after the request that granted or renewed it was sent, which is no later than discovery
+/// expires the instance, so an instance that can't reach discovery stops accepting joins before
+/// its terms are moved elsewhere.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Lease {
+    /// Increases with every lease granted, so a later registration always has a higher epoch.
+    pub epoch: i64,
+    pub duration_ms: u64,
 }
 
 #[derive(Copy, Clone, Deserialize, Serialize)]
@@ -23,23 +39,111 @@ pub enum PingResult {
     NoLongerActive,
 }
 
-#[derive(Copy, Clone, Deserialize, Serialize)]
+/// The load an instance reports with every ping. Discovery uses it to place new chatrooms.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Load {
+    /// The number of chatrooms open on the instance.
+    pub chatrooms: u32,
+    /// The number of users connected to those chatrooms.
+    pub users: u32,
+    /// The CPU usage of the host as a percentage.
+    pub cpu: f32,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct PingRequest {
     pub address: SocketAddrV4,
+    #[serde(default)]
+    pub region: Option<String>,
     pub instance_id: i32,
+    #[serde(default)]
+    pub load: Load,
+    /// The epoch of the lease to renew.
+    #[serde(default)]
+    pub epoch: i64,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct PingResponse {
     pub ping_result: PingResult,
+    /// The renewed lease. Missing when the instance is no longer active.
+    #[serde(default)]
+    pub lease: Option<Lease>,
+}
+
+/// Sent by an instance that is shutting down so that its chatrooms are reassigned right away
+/// rather than once its registration expires.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DeregisterRequest {
+    pub address: SocketAddrV4,
+    #[serde(default)]
+    pub region: Option<String>,
+    pub instance_id: i32,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ChatroomRequest {
     pub term: String,
+    /// The region the client prefers. Terms are placed in other regions only while it has no
+    /// active instances.
+    #[serde(default)]
+    pub region: Option<String>,
 }
 
 #[derive(Copy, Clone, Deserialize, Serialize)]
 pub struct ChatroomResponse {
     pub instance: Option<Instance>,
 }
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BatchChatroomRequest {
+    pub terms: Vec<String>,
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BatchChatroomResponse {
+    /// The instance hosting the chatroom of every term. Terms that could not be placed, because
+    /// there are no active instances, are left out.
+    pub instances: HashMap<String, Instance>,
+}
+
+/// An active instance along with the load it last reported.
+#[derive(Copy, Clone, Deserialize, Serialize)]
+pub struct InstanceStatus {
+    pub instance: Instance,
+    pub load: Load,
+}
+
+/// The active instances of every region, as returned by the /membership endpoint of discovery.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MembershipResponse {
+    /// Increases every time an instance joins or leaves. Pass it back to wait for the next
+    /// change.
+    pub version: u64,
+    /// Keyed by region.
+    pub instances: HashMap<String, Vec<InstanceStatus>>,
+}
+
+/// A record of a term being assigned to an instance.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Placement {
+    pub term: String,
+    /// The region whose mapping was updated.
+    pub region: String,
+    pub instance_id: i32,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: i64,
+    /// Every instance that was considered, with the load that was assumed for it.
+    pub candidates: Vec<InstanceStatus>,
+}
+
+/// The current state of placement, returned by discovery for debugging.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PlacementsResponse {
+    /// The active instances of every known region, keyed by region.
+    pub instances: HashMap<String, Vec<InstanceStatus>>,
+    /// The most recent placements, newest last.
+    pub placements: Vec<Placement>,
+}
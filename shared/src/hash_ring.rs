@@ -0,0 +1,94 @@
+// A consistent hashing ring that assigns terms to chatroom instances. Every instance is hashed
+// onto the ring at a number of points, its virtual nodes, and a term belongs to the first
+// instance found walking clockwise from the hash of the term. When an instance joins or leaves
+// only the terms next to its points move, which is about 1/N of them.
+//
+// The ring is a pure function of the membership list, so every service that knows the active
+// instances computes the same owner for a term without asking the discovery service.
+
+use crate::discovery::Instance;
+use crate::get_channel_id;
+
+pub struct HashRing {
+    // Sorted by the position on the ring.
+    nodes: Vec<(i32, Instance)>,
+}
+
+impl HashRing {
+    pub fn new(instances: &[Instance], virtual_nodes: u32) -> Self {
+        let mut nodes = Vec::with_capacity(instances.len() * virtual_nodes as usize);
+
+        for instance in instances {
+            // Points are derived from the address rather than the instance id, so an instance
+            // that restarts and registers again gets its old terms back.
+            for replica in 0..virtual_nodes {
+                let point = get_channel_id(&format!("{}-{}", instance.address, replica));
+                nodes.push((point, *instance));
+            }
+        }
+
+        nodes.sort_by_key(|(point, instance)| (*point, instance.address));
+
+        HashRing { nodes }
+    }
+
+    /// Returns the instance that owns the term, or None if the ring is empty.
+    pub fn get(&self, term: &str) -> Option<&Instance> {
+        self.get_by_channel_id(get_channel_id(term))
+    }
+
+    /// Returns the instance that owns the chatroom with the given id.
+    pub fn get_by_channel_id(&self, channel_id: i32) -> Option<&Instance> {
+        let index = self.nodes.partition_point(|(point, _)| *point < channel_id);
+
+        self.nodes
+            .get(index)
+            .or_else(|| self.nodes.first())
+            .map(|(_point, instance)| instance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::Instance;
+    use crate::hash_ring::HashRing;
+    use std::net::SocketAddrV4;
+
+    fn instances(count: u16) -> Vec<Instance> {
+        (0..count)
+            .map(|index| Instance {
+                instance_id: index as i32,
+                address: SocketAddrV4::new("127.0.0.1".parse().unwrap(), 3000 + index),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_ring_has_no_owner() {
+        let ring = HashRing::new(&[], 64);
+        assert!(ring.get("rust").is_none());
+    }
+
+    #[test]
+    fn joining_instance_only_takes_its_share() {
+        let before = HashRing::new(&instances(4), 64);
+        let after = HashRing::new(&instances(5), 64);
+
+        let terms: Vec<String> = (0..1000).map(|index| format!("term{}", index)).collect();
+        let mut moved = 0;
+
+        for term in &terms {
+            let old_owner = before.get(term).unwrap().instance_id;
+            let new_owner = after.get(term).unwrap().instance_id;
+
+            if old_owner != new_owner {
+                // Terms only ever move to the instance that joined.
+                assert_eq!(new_owner, 4);
+                moved += 1;
+            }
+        }
+
+        // About a fifth of the terms should move.
+        assert!(moved > 100 && moved < 300, "{} terms moved", moved);
+    }
+}
@@ -12,24 +12,112 @@ use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::io::Cursor;
 
+pub mod config;
 pub mod discovery;
+pub mod hash_ring;
+pub mod migration;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
 pub enum ClientToServerMessage {
-    Join { chatroom_id: i32 },
-    NewMessage { content: String },
-    ChatsFromTodayRequest,
+    Join {
+        chatroom_id: i32,
+        /// The term of the chatroom. When given, the instance checks with discovery that it
+        /// hosts the chatroom and redirects the client otherwise.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        term: Option<String>,
+        /// The region the chatroom was looked up in.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<String>,
+    },
+    NewMessage {
+        content: String,
+        /// Chosen by the client so that a message resent after a reconnect is only delivered
+        /// once.
+        #[serde(default)]
+        idempotency_key: Option<String>,
+    },
+    /// Requests up to `limit` chats sent before the `before` cursor, or the most recent chats
+    /// if no cursor is given.
+    HistoryRequest {
+        #[serde(default)]
+        before: Option<HistoryCursor>,
+        limit: u32,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
 pub enum ServerToClientMessage {
-    Joined { chatroom_id: i32 },
-    NewUser { user_id: i32 },
-    UserDisconnected { user_id: i32 },
-    NewMessage { content: String },
-    ChatsFromTodayResponse { messages: Vec<String> },
+    Joined {
+        chatroom_id: i32,
+    },
+    NewUser {
+        user_id: i32,
+    },
+    UserDisconnected {
+        user_id: i32,
+    },
+    NewMessage {
+        message: ChatMessage,
+    },
+    /// Sent to the sender of a message with an idempotency key once the message is stored.
+    /// Duplicates are acknowledged with the id of the original message.
+    MessageAck {
+        idempotency_key: String,
+        message_id: i64,
+    },
+    /// Sent when the instance hosting the chatroom is going away. The client should join the
+    /// chatroom at `url`, or look the chatroom up again if no url is given.
+    Reconnect {
+        url: Option<String>,
+    },
+    /// Sent instead of joining when the instance no longer holds the lease on its terms, which
+    /// may have been moved to another instance. The client should look the chatroom up again.
+    Moved,
+    /// Sent instead of joining when the chatroom is hosted by another instance. The client
+    /// should join the chatroom at `url` instead.
+    Redirect {
+        url: String,
+    },
+    /// Chats are ordered oldest first. Passing `next_cursor` as `before` fetches the page of
+    /// older chats. It is missing once the start of the chatroom's history has been reached.
+    HistoryResponse {
+        messages: Vec<ChatMessage>,
+        next_cursor: Option<HistoryCursor>,
+    },
+}
+
+/// The largest message id. Ids fit in the 53 bits that JavaScript numbers hold exactly, so
+/// clients parsing them from JSON see the same id as the server.
+pub const MAX_MESSAGE_ID: i64 = (1 << 53) - 1;
+
+/// A chat sent to a chatroom. Everything except the content is assigned by the server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChatMessage {
+    /// Between 1 and MAX_MESSAGE_ID. Chats stored before ids were assigned have an id of 0.
+    pub message_id: i64,
+    pub user_id: i32,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: i64,
+    pub content: String,
+}
+
+/// The position of a chat in the history of its chatroom. Chats are ordered by when they were
+/// sent and then by id, so chats sent in the same millisecond each have their own position.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HistoryCursor {
+    pub timestamp: i64,
+    pub message_id: i64,
+}
+
+impl HistoryCursor {
+    pub fn of(chat: &ChatMessage) -> Self {
+        HistoryCursor {
+            timestamp: chat.timestamp,
+            message_id: chat.message_id,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -39,6 +127,64 @@ pub struct Chatroom {
     pub online: bool,
     pub term: String,
     pub url: String,
+    #[serde(default)]
+    pub kind: ChatroomKind,
+    /// The number of messages sent to the chatroom recently.
+    #[serde(default)]
+    pub recent_messages: u32,
+}
+
+/// The response of the frontend server to a search.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatroomsResponse {
+    /// The chatrooms found, best first.
+    pub chatrooms: Vec<Chatroom>,
+    /// Whether the chatroom of each term looked up could be found, including those of terms
+    /// that didn't make it into `chatrooms`.
+    pub terms: Vec<TermStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TermStatus {
+    pub term: String,
+    pub kind: ChatroomKind,
+    pub status: LookupStatus,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LookupStatus {
+    Ok,
+    /// The term is mapped to an instance that didn't answer in time.
+    InstanceUnreachable,
+    /// Discovery had no instance to map the term to.
+    NoInstanceAvailable,
+    /// Discovery didn't answer in time, so the term couldn't be mapped at all.
+    DiscoveryUnreachable,
+}
+
+/// How busy a chatroom is, as reported by the instance hosting it.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChatroomActivity {
+    pub chatroom_id: i32,
+    pub num_users: u32,
+    /// The number of messages sent within the activity window of the instance.
+    pub recent_messages: u32,
+}
+
+/// What part of a search the term of a chatroom was taken from, or why it was suggested.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatroomKind {
+    /// A single word of the search.
+    #[default]
+    Word,
+    /// A few adjacent words of the search.
+    Phrase,
+    /// The whole search.
+    Query,
+    /// A word often searched for together with those of the search.
+    Related,
 }
 
 pub fn get_channel_id(term: &str) -> i32 {
@@ -47,7 +193,7 @@ pub fn get_channel_id(term: &str) -> i32 {
     let hash = context.finish();
     let bytes = hash.as_ref();
     let mut cursor = Cursor::new(bytes);
-    return cursor.read_i32::<LittleEndian>().unwrap();
+    cursor.read_i32::<LittleEndian>().unwrap()
 }
 
 pub fn initialize_logger() -> Result<(), Box<dyn Error + Send + Sync>> {
@@ -75,7 +221,11 @@ mod tests {
 
     #[test]
     fn serialize_and_deserialize() {
-        let message = ClientToServerMessage::Join { chatroom_id: 6969 };
+        let message = ClientToServerMessage::Join {
+            chatroom_id: 6969,
+            term: None,
+            region: None,
+        };
         let serialized = serde_json::to_string(&message).unwrap();
         assert_eq!(serialized, r#"{"type":"Join","chatroom_id":6969}"#);
         let _deserialize: ClientToServerMessage = serde_json::from_str(&serialized).unwrap();
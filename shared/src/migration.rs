@@ -0,0 +1,377 @@
+// Every service keeps its tables in one keyspace and describes their schema as an ordered list
+// of versioned migrations. The versions applied so far are recorded per service in the
+// schema_version table, so each migration runs once no matter how many instances start.
+//
+// Migrations are applied either on startup or by running a service with the migrate
+// subcommand. Two instances starting at once may both apply a migration before either has
+// recorded it, so every step must be safe to run twice, e.g. CREATE TABLE IF NOT EXISTS.
+//
+// The keyspace is shared by every service, so its replication is only ever changed by the
+// migrate subcommand. Were services to alter it on startup, two services configured with
+// different replication would keep changing it back and forth.
+
+use log::{info, warn};
+use scylla::{IntoTypedRows, Session};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+
+type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// The future returned by a [`Step::Task`].
+pub type TaskFuture<'a> = Pin<Box<dyn Future<Output = BoxResult<()>> + Send + 'a>>;
+
+/// A change to the schema of a service. `C` is passed to the tasks of the migration, which is
+/// usually the configuration of the service.
+pub struct Migration<C: 'static = ()> {
+    pub version: i32,
+    pub description: &'static str,
+    pub steps: &'static [Step<C>],
+}
+
+pub enum Step<C = ()> {
+    /// A CQL statement run against the keyspace of the service.
+    Cql(&'static str),
+    /// Adds a column to a table unless it already has it. Columns can't be added with IF NOT
+    /// EXISTS, so this is the idempotent way of doing it.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        cql_type: &'static str,
+    },
+    /// Arbitrary work such as copying data between tables.
+    Task(for<'a> fn(&'a Session, &'a C) -> TaskFuture<'a>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationStrategy {
+    /// Replicas are placed on consecutive nodes of the ring. Only suitable for a single
+    /// datacenter.
+    Simple,
+    /// The replication factor applies to each of the listed datacenters.
+    NetworkTopology,
+}
+
+/// The replication of the keyspace shared by the services.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Replication {
+    pub strategy: ReplicationStrategy,
+    /// The number of replicas, in every datacenter when using network_topology.
+    pub factor: u32,
+    /// The datacenters to replicate to when using network_topology.
+    pub datacenters: Vec<String>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication {
+            strategy: ReplicationStrategy::Simple,
+            factor: 1,
+            datacenters: Vec::new(),
+        }
+    }
+}
+
+impl Replication {
+    fn options(&self) -> BoxResult<Vec<(String, String)>> {
+        let factor = self.factor.to_string();
+
+        match self.strategy {
+            ReplicationStrategy::Simple => Ok(vec![
+                ("class".to_string(), "SimpleStrategy".to_string()),
+                ("replication_factor".to_string(), factor),
+            ]),
+            ReplicationStrategy::NetworkTopology => {
+                if self.datacenters.is_empty() {
+                    return Err(
+                        "network_topology replication needs at least one datacenter.".into(),
+                    );
+                }
+
+                let mut options =
+                    vec![("class".to_string(), "NetworkTopologyStrategy".to_string())];
+                for datacenter in &self.datacenters {
+                    options.push((datacenter.clone(), factor.clone()));
+                }
+                Ok(options)
+            }
+        }
+    }
+
+    /// Returns the replication map used when creating or altering a keyspace.
+    pub fn to_cql(&self) -> BoxResult<String> {
+        let options: Vec<String> = self
+            .options()?
+            .iter()
+            .map(|(key, value)| format!("'{}': '{}'", key, value))
+            .collect();
+
+        Ok(format!("{{{}}}", options.join(", ")))
+    }
+
+    /// Returns whether a keyspace with the replication read from system_schema.keyspaces is
+    /// already replicated this way.
+    pub fn matches(&self, current: &HashMap<String, String>) -> BoxResult<bool> {
+        let options = self.options()?;
+
+        let matches = options.len() == current.len()
+            && options.iter().all(|(key, value)| match current.get(key) {
+                // The class is stored with its package name.
+                Some(current) if key == "class" => current.rsplit('.').next() == Some(value),
+                Some(current) => current == value,
+                None => false,
+            });
+
+        Ok(matches)
+    }
+}
+
+/// Creates the keyspace with the configured replication, then applies the migrations of the
+/// service that haven't been applied yet. When the keyspace exists with a different
+/// replication it is altered if `alter_replication` is set, which only the migrate subcommand
+/// does, and left alone with a warning otherwise. The session is left using the keyspace.
+pub async fn run<C: Sync + 'static>(
+    session: &Session,
+    keyspace: &str,
+    service: &str,
+    replication: &Replication,
+    alter_replication: bool,
+    migrations: &[Migration<C>],
+    context: &C,
+) -> BoxResult<()> {
+    if migrations
+        .windows(2)
+        .any(|pair| pair[0].version >= pair[1].version)
+    {
+        return Err(format!("Migrations of {} are not in version order.", service).into());
+    }
+
+    configure_keyspace(session, keyspace, replication, alter_replication).await?;
+    session.use_keyspace(keyspace, false).await?;
+
+    session
+        .query(
+            r#"
+            CREATE TABLE IF NOT EXISTS schema_version (
+                service text,
+                version int,
+                description text,
+                applied_at timestamp,
+                PRIMARY KEY(service, version),
+            );
+            "#,
+            (),
+        )
+        .await?;
+
+    let rows = session
+        .query(
+            "SELECT version FROM schema_version WHERE service = ?",
+            (service,),
+        )
+        .await?
+        .rows
+        .unwrap_or_default()
+        .into_typed::<(i32,)>();
+
+    let mut applied = Vec::new();
+    for row in rows {
+        let (version,) = row?;
+        applied.push(version);
+    }
+
+    for migration in migrations {
+        if applied.contains(&migration.version) {
+            continue;
+        }
+
+        info!(
+            "Applying migration {} of {} - {}",
+            migration.version, service, migration.description
+        );
+
+        for step in migration.steps {
+            run_step(session, keyspace, step, context).await?;
+        }
+
+        session
+            .query(
+                r#"
+                INSERT INTO schema_version (service, version, description, applied_at)
+                VALUES (?, ?, ?, toTimestamp(now()))
+                "#,
+                (service, migration.version, migration.description),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn configure_keyspace(
+    session: &Session,
+    keyspace: &str,
+    replication: &Replication,
+    alter_replication: bool,
+) -> BoxResult<()> {
+    let mut rows = session
+        .query(
+            "SELECT replication FROM system_schema.keyspaces WHERE keyspace_name = ?",
+            (keyspace,),
+        )
+        .await?
+        .rows
+        .unwrap_or_default()
+        .into_typed::<(HashMap<String, String>,)>();
+
+    match rows.next() {
+        None => {
+            info!("Creating keyspace {}.", keyspace);
+            session
+                .query(
+                    format!(
+                        "CREATE KEYSPACE IF NOT EXISTS {} WITH REPLICATION = {}",
+                        keyspace,
+                        replication.to_cql()?
+                    ),
+                    (),
+                )
+                .await?;
+        }
+        Some(row) => {
+            let (current,) = row?;
+
+            if replication.matches(&current)? {
+                return Ok(());
+            }
+
+            if !alter_replication {
+                warn!(
+                    "Keyspace {} is replicated as {:?} rather than as configured. Run the migrate subcommand to change it.",
+                    keyspace, current
+                );
+            } else {
+                // Existing data is only moved to its new replicas by a repair.
+                warn!(
+                    "Changing the replication of keyspace {} from {:?}. Run a repair to move existing data.",
+                    keyspace, current
+                );
+                session
+                    .query(
+                        format!(
+                            "ALTER KEYSPACE {} WITH REPLICATION = {}",
+                            keyspace,
+                            replication.to_cql()?
+                        ),
+                        (),
+                    )
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_step<C: Sync + 'static>(
+    session: &Session,
+    keyspace: &str,
+    step: &Step<C>,
+    context: &C,
+) -> BoxResult<()> {
+    match step {
+        Step::Cql(statement) => {
+            session.query(*statement, ()).await?;
+        }
+        Step::AddColumn {
+            table,
+            column,
+            cql_type,
+        } => {
+            let exists = session
+                .query(
+                    r#"
+                    SELECT column_name FROM system_schema.columns
+                    WHERE keyspace_name = ? AND table_name = ? AND column_name = ?"#,
+                    (keyspace, *table, *column),
+                )
+                .await?
+                .rows
+                .is_some_and(|rows| !rows.is_empty());
+
+            if !exists {
+                session
+                    .query(
+                        format!("ALTER TABLE {} ADD {} {}", table, column, cql_type),
+                        (),
+                    )
+                    .await?;
+            }
+        }
+        Step::Task(task) => {
+            task(session, context).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replication_is_rendered_as_cql() {
+        let replication = Replication::default();
+        assert_eq!(
+            replication.to_cql().unwrap(),
+            "{'class': 'SimpleStrategy', 'replication_factor': '1'}"
+        );
+
+        let replication = Replication {
+            strategy: ReplicationStrategy::NetworkTopology,
+            factor: 3,
+            datacenters: vec!["us-east".to_string(), "eu-west".to_string()],
+        };
+        assert_eq!(
+            replication.to_cql().unwrap(),
+            "{'class': 'NetworkTopologyStrategy', 'us-east': '3', 'eu-west': '3'}"
+        );
+
+        let replication = Replication {
+            strategy: ReplicationStrategy::NetworkTopology,
+            ..Replication::default()
+        };
+        assert!(replication.to_cql().is_err());
+    }
+
+    #[test]
+    fn replication_matches_existing_keyspace() {
+        let current: HashMap<String, String> = [
+            ("class", "org.apache.cassandra.locator.SimpleStrategy"),
+            ("replication_factor", "1"),
+        ]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+        assert!(Replication::default().matches(&current).unwrap());
+
+        let replication = Replication {
+            factor: 3,
+            ..Replication::default()
+        };
+        assert!(!replication.matches(&current).unwrap());
+
+        let replication = Replication {
+            strategy: ReplicationStrategy::NetworkTopology,
+            factor: 1,
+            datacenters: vec!["us-east".to_string()],
+        };
+        assert!(!replication.matches(&current).unwrap());
+    }
+}